@@ -20,16 +20,32 @@ pub const PUSH_NULL: u8 = 0x20;
 pub const PUSH_INT: u8 = 0x21;
 pub const PUSH_CONST: u8 = 0x22;
 pub const NEW_ARRAY: u8 = 0x23;
+pub const CONCAT: u8 = 0x24;
 
 pub const PUSH_ARG: u8 = 0x28;
 pub const PUSH_SELF: u8 = 0x29;
 pub const PUSH_SUPER: u8 = 0x2a;
 pub const PUSH_CLOSURE: u8 = 0x2b;
+pub const PUSH_ARGS: u8 = 0x2c;
+pub const CHECK_ARGS: u8 = 0x2d;
+pub const PUSH_THIS: u8 = 0x2e;
+pub const CALL_METHOD: u8 = 0x2f;
 
 pub const JMP: u8 = 0x30;
 pub const JN: u8 = 0x31;
 pub const JT: u8 = 0x32;
 pub const JF: u8 = 0x33;
+pub const LOOP: u8 = 0x34;
+pub const END_LOOP: u8 = 0x35;
+pub const BREAK: u8 = 0x36;
+pub const CONTINUE: u8 = 0x37;
+
+pub const TRY: u8 = 0x3a;
+pub const END_TRY: u8 = 0x3b;
+pub const BLOCK: u8 = 0x3c;
+pub const END_BLOCK: u8 = 0x3d;
+pub const THROW: u8 = 0x3e;
+pub const END_FINALLY: u8 = 0x3f;
 
 pub const CALL: u8 = 0x38;
 pub const RETURN: u8 = 0x39;
@@ -40,6 +56,8 @@ pub const MUL: u8 = 0x42;
 pub const DIV: u8 = 0x43;
 pub const MOD: u8 = 0x44;
 pub const NEG: u8 = 0x45;
+pub const POW: u8 = 0x46;
+pub const IDIV: u8 = 0x47;
 
 pub const CMP_EQ: u8 = 0x48;
 pub const CMP_NE: u8 = 0x49;
@@ -62,6 +80,7 @@ pub const LEN: u8 = 0x59;
 pub const IN: u8 = 0x60;
 pub const OUT: u8 = 0x61;
 pub const LOAD_LIB: u8 = 0x62;
+pub const OUT_REPR: u8 = 0x63;
 
 pub struct CodeInfo {
     pub name: &'static str,
@@ -105,7 +124,7 @@ pub const CODE_INFO: &[CodeInfo] = &[
     CodeInfo { name: "PUSH_INT", params: 1 },
     CodeInfo { name: "PUSH_CONST", params: 1 },
     CodeInfo { name: "NEW_ARRAY", params: 1 },
-    CodeInfo { name: "0x24", params: 0 },
+    CodeInfo { name: "CONCAT", params: 1 },
     CodeInfo { name: "0x25", params: 0 },
     CodeInfo { name: "0x26", params: 0 },
     CodeInfo { name: "0x27", params: 0 },
@@ -113,34 +132,34 @@ pub const CODE_INFO: &[CodeInfo] = &[
     CodeInfo { name: "PUSH_SELF", params: 0 },
     CodeInfo { name: "PUSH_SUPER", params: 1 },
     CodeInfo { name: "PUSH_CLOSURE", params: 1 },
-    CodeInfo { name: "0x2c", params: 0 },
-    CodeInfo { name: "0x2d", params: 0 },
-    CodeInfo { name: "0x2e", params: 0 },
-    CodeInfo { name: "0x2f", params: 0 },
-    CodeInfo { name: "JMP", params: 1 },
-    CodeInfo { name: "JN", params: 1 },
-    CodeInfo { name: "JT", params: 1 },
-    CodeInfo { name: "JF", params: 1 },
-    CodeInfo { name: "0x34", params: 0 },
-    CodeInfo { name: "0x35", params: 0 },
-    CodeInfo { name: "0x36", params: 0 },
-    CodeInfo { name: "0x37", params: 0 },
+    CodeInfo { name: "PUSH_ARGS", params: 1 },
+    CodeInfo { name: "CHECK_ARGS", params: 1 },
+    CodeInfo { name: "PUSH_THIS", params: 0 },
+    CodeInfo { name: "CALL_METHOD", params: 2 },
+    CodeInfo { name: "JMP", params: 2 },
+    CodeInfo { name: "JN", params: 2 },
+    CodeInfo { name: "JT", params: 2 },
+    CodeInfo { name: "JF", params: 2 },
+    CodeInfo { name: "LOOP", params: 2 },
+    CodeInfo { name: "END_LOOP", params: 0 },
+    CodeInfo { name: "BREAK", params: 4 },
+    CodeInfo { name: "CONTINUE", params: 4 },
     CodeInfo { name: "CALL", params: 1 },
     CodeInfo { name: "RETURN", params: 0 },
-    CodeInfo { name: "0x3a", params: 0 },
-    CodeInfo { name: "0x3b", params: 0 },
-    CodeInfo { name: "0x3c", params: 0 },
-    CodeInfo { name: "0x3d", params: 0 },
-    CodeInfo { name: "0x3e", params: 0 },
-    CodeInfo { name: "0x3f", params: 0 },
+    CodeInfo { name: "TRY", params: 2 },
+    CodeInfo { name: "END_TRY", params: 0 },
+    CodeInfo { name: "BLOCK", params: 2 },
+    CodeInfo { name: "END_BLOCK", params: 0 },
+    CodeInfo { name: "THROW", params: 0 },
+    CodeInfo { name: "END_FINALLY", params: 0 },
     CodeInfo { name: "ADD", params: 0 },
     CodeInfo { name: "SUB", params: 0 },
     CodeInfo { name: "MUL", params: 0 },
     CodeInfo { name: "DIV", params: 0 },
     CodeInfo { name: "MOD", params: 0 },
     CodeInfo { name: "NEG", params: 0 },
-    CodeInfo { name: "0x46", params: 0 },
-    CodeInfo { name: "0x47", params: 0 },
+    CodeInfo { name: "POW", params: 0 },
+    CodeInfo { name: "IDIV", params: 0 },
     CodeInfo { name: "CMP_EQ", params: 0 },
     CodeInfo { name: "CMP_NE", params: 0 },
     CodeInfo { name: "CMP_GT", params: 0 },
@@ -168,4 +187,5 @@ pub const CODE_INFO: &[CodeInfo] = &[
     CodeInfo { name: "IN", params: 0 },
     CodeInfo { name: "OUT", params: 0 },
     CodeInfo { name: "LOAD_LIB", params: 1 },
+    CodeInfo { name: "OUT_REPR", params: 0 },
 ];
@@ -59,8 +59,28 @@ impl ConstantPool {
     }
 }
 
+/// An entry of a function's exception table: errors raised by the code in
+/// `start .. end` are handled by the code at `target`.
+#[derive(Clone, Copy)]
+pub struct Handler {
+    pub start: usize,
+    pub end: usize,
+    pub target: usize,
+    // depth of the `TRY` block among the function's loops and blocks
+    pub depth: u8,
+    // `finally` code, which also runs for errors that cannot be caught
+    pub cleanup: bool
+}
+
+impl Handler {
+    pub fn covers(&self, pc: usize) -> bool {
+        self.start <= pc && pc < self.end
+    }
+}
+
 struct Func {
     code: Vec<u8>,
+    handlers: Vec<Handler>,
     arg_idx: u32
 }
 
@@ -68,6 +88,7 @@ impl Func {
     fn new() -> Self {
         Self {
             code: vec![],
+            handlers: vec![],
             arg_idx: 0
         }
     }
@@ -155,6 +176,15 @@ impl Program {
         Ok(())
     }
 
+    /// Pushes the arguments from the next positional one on as an array.
+    pub fn push_rest_args(&mut self) -> Result<(), GeneratingError> {
+        let idx = self.current_func().arg_idx.try_into()
+            .map_err(|_| GeneratingError::ArgumentListExceeding)?;
+        self.byte(code::PUSH_ARGS);
+        self.byte(idx);
+        Ok(())
+    }
+
     pub fn push_closure_and_switch(&mut self) -> Result<(), GeneratingError> {
         let idx = self.func_list.len().try_into()
             .map_err(|_| GeneratingError::ClosureListExceeding)?;
@@ -172,43 +202,67 @@ impl Program {
         self.idx.pop();
     }
 
+    /// Nesting level of the function being generated, the main one being 1.
+    pub fn func_level(&self) -> usize {
+        self.idx.len()
+    }
+
     pub fn get_pos(&self) -> usize {
         self.current_func().code.len()
     }
 
+    // jump offsets are i16, little endian, relative to the offset itself
+
     pub fn jump_back(&mut self, pos: usize) -> Result<(), GeneratingError> {
         let delta: i32 = (self.get_pos() - pos).try_into()
             .map_err(|_| GeneratingError::JumpingTooFar)?;
-        let delta: i8 = (-delta).try_into()
+        let delta: i16 = (-delta).try_into()
             .map_err(|_| GeneratingError::JumpingTooFar)?;
-        self.byte(delta as u8);
+        for byte in delta.to_le_bytes() {
+            self.byte(byte);
+        }
         Ok(())
     }
 
     pub fn jump_where(&mut self) -> JumpWhere {
         let pos = self.get_pos();
         self.byte(0);
+        self.byte(0);
         JumpWhere { pos }
     }
 
     pub fn jump_here(&mut self, jump: JumpWhere) -> Result<(), GeneratingError> {
-        let delta: i8 = (self.get_pos() - jump.pos).try_into()
+        let delta: i16 = (self.get_pos() - jump.pos).try_into()
             .map_err(|_| GeneratingError::JumpingTooFar)?;
-        self.current_func_mut().code[jump.pos] = delta as u8;
+        self.current_func_mut().code[jump.pos .. jump.pos + 2].copy_from_slice(&delta.to_le_bytes());
         Ok(())
     }
 
+    /// Adds an entry to the exception table of the current function. Inner
+    /// handlers must be added before the outer ones, as the first match wins.
+    pub fn add_handler(&mut self, handler: Handler) {
+        self.current_func_mut().handlers.push(handler);
+    }
+
     pub fn bundle(self) -> ProgramBundle {
         ProgramBundle {
             constant_pool: self.constant_pool.constant_list,
-            func_list: self.func_list.into_iter().map(|f| f.code).collect()
+            func_list: self.func_list.into_iter().map(|f| CompiledFunc {
+                code: f.code,
+                handlers: f.handlers
+            }).collect()
         }
     }
 }
 
+pub struct CompiledFunc {
+    pub code: Vec<u8>,
+    pub handlers: Vec<Handler>
+}
+
 pub struct ProgramBundle {
     pub constant_pool: Vec<Constant>,
-    pub func_list: Vec<Vec<u8>>
+    pub func_list: Vec<CompiledFunc>
 }
 
 impl ProgramBundle {
@@ -230,19 +284,162 @@ impl ProgramBundle {
         for (idx, func) in self.func_list.iter().enumerate() {
             eprintln!();
             eprintln!("  #{idx}:");
+            let code = &func.code;
             let mut idx = 0;
-            while idx < func.len() {
-                let info = &code::CODE_INFO[func[idx] as usize];
+            while idx < code.len() {
+                let info = &code::CODE_INFO[code[idx] as usize];
                 idx += 1;
                 eprint!("    {}", info.name);
                 for _ in 0..info.params {
-                    eprint!(" {:#x}", func[idx]);
+                    eprint!(" {:#x}", code[idx]);
                     idx += 1;
                 }
                 eprintln!();
             }
+            for handler in &func.handlers {
+                eprintln!("    handler {:#x}..{:#x} -> {:#x}, depth {}{}",
+                    handler.start, handler.end, handler.target, handler.depth,
+                    if handler.cleanup { ", cleanup" } else { "" });
+            }
         }
         eprintln!();
         eprintln!();
     }
 }
+
+const BUNDLE_MAGIC: &[u8; 4] = b"CUTE";
+/// Bumped whenever the serialized layout or the instruction set changes.
+pub const BUNDLE_FORMAT: u8 = 6;
+
+const TAG_INT: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_STRING: u8 = 2;
+
+#[derive(Debug)]
+pub struct BundleFormatError;
+
+struct Reader<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], BundleFormatError> {
+        if self.data.len() < len {
+            return Err(BundleFormatError);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, BundleFormatError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, BundleFormatError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, BundleFormatError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+impl ProgramBundle {
+    /// Serializes the bundle into a compact binary form, see `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(BUNDLE_MAGIC);
+        out.push(BUNDLE_FORMAT);
+
+        out.extend_from_slice(&(self.constant_pool.len() as u32).to_le_bytes());
+        for constant in &self.constant_pool {
+            match constant {
+                Constant::Int(v) => {
+                    out.push(TAG_INT);
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+                Constant::Float(v) => {
+                    out.push(TAG_FLOAT);
+                    out.extend_from_slice(&v.to_bits().to_le_bytes());
+                }
+                Constant::String(v) => {
+                    out.push(TAG_STRING);
+                    out.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                    for unit in v.iter() {
+                        out.extend_from_slice(&unit.to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        out.extend_from_slice(&(self.func_list.len() as u32).to_le_bytes());
+        for func in &self.func_list {
+            out.extend_from_slice(&(func.code.len() as u32).to_le_bytes());
+            out.extend_from_slice(&func.code);
+            out.extend_from_slice(&(func.handlers.len() as u32).to_le_bytes());
+            for handler in &func.handlers {
+                for pos in [handler.start, handler.end, handler.target] {
+                    out.extend_from_slice(&(pos as u32).to_le_bytes());
+                }
+                out.push(handler.depth);
+                out.push(handler.cleanup.into());
+            }
+        }
+
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BundleFormatError> {
+        let mut reader = Reader { data };
+
+        if reader.bytes(4)? != BUNDLE_MAGIC || reader.u8()? != BUNDLE_FORMAT {
+            return Err(BundleFormatError);
+        }
+
+        let mut constant_pool = vec![];
+        for _ in 0..reader.u32()? {
+            let constant = match reader.u8()? {
+                TAG_INT => Constant::Int(reader.u64()? as i64),
+                TAG_FLOAT => Constant::Float(f64::from_bits(reader.u64()?)),
+                TAG_STRING => {
+                    let len = reader.u32()?;
+                    let str = reader.bytes(len.checked_mul(2).ok_or(BundleFormatError)?)?
+                        .chunks_exact(2)
+                        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                        .collect();
+                    Constant::String(str)
+                }
+                _ => return Err(BundleFormatError)
+            };
+            constant_pool.push(constant);
+        }
+
+        let mut func_list = vec![];
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()?;
+            let code = reader.bytes(len)?.to_vec();
+            let mut handlers = vec![];
+            for _ in 0..reader.u32()? {
+                handlers.push(Handler {
+                    start: reader.u32()?,
+                    end: reader.u32()?,
+                    target: reader.u32()?,
+                    depth: reader.u8()?,
+                    cleanup: match reader.u8()? {
+                        0 => false,
+                        1 => true,
+                        _ => return Err(BundleFormatError)
+                    }
+                });
+            }
+            func_list.push(CompiledFunc { code, handlers });
+        }
+
+        if !reader.data.is_empty() {
+            return Err(BundleFormatError);
+        }
+
+        Ok(Self { constant_pool, func_list })
+    }
+}
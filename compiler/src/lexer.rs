@@ -1,16 +1,37 @@
+use unicode_xid::UnicodeXID;
+use unicode_normalization::UnicodeNormalization;
+use std::fmt;
+
 pub struct Lexer<'a> {
     input: std::iter::Peekable<std::str::Chars<'a>>,
-    peeked: Option<Token>
+    peeked: Option<Token>,
+    // tokens handed back to the lexer, the next one last
+    pending: Vec<Token>,
+    // brace depth inside each open interpolation hole
+    holes: Vec<u32>,
+    warnings: Vec<LexerWarning>
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum Token {
     Eq, Ne, Ge, Le, And, Or,
     Shl, Shr,
+    Pow, IDiv,
+    // `??`, `?.`, `?[` and `?(`
+    Coalesce, OptDot, OptIndex, OptCall,
+    // `...`
+    Ellipsis,
+    // `<<<`, printing a value like a literal
+    OutRepr,
+    // compound assignment, e.g. `OpAssign('+')` for `+=`
+    OpAssign(char),
+    ShlAssign, ShrAssign,
     Single(char),
     Integer(i64),
     Float(f64),
     String(String),
+    Template(String),
+    TemplateEnd(String),
     Name(String),
     EOF
 }
@@ -19,10 +40,137 @@ pub enum Token {
 pub enum LexerError {
     CharacterError(char),
     MultiCommentError,
-    NumberError,
+    IntegerOverflow(String),
+    MalformedNumber(String),
     StringError
 }
 
+#[derive(Debug)]
+pub enum LexerWarning {
+    /// An octal literal written with a bare leading `0`, such as `017`
+    /// instead of `0o17`.
+    LegacyOctal(i64)
+}
+
+impl fmt::Display for LexerWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LegacyOctal(num) =>
+                write!(f, "octal literal with a leading `0` is deprecated, write `0o{:o}`", num)
+        }
+    }
+}
+
+fn to_integer(digits: &str, radix: u32, prefix: &str) -> Result<Token, LexerError> {
+    i64::from_str_radix(digits, radix).map(Token::Integer).map_err(|e| {
+        let literal = format!("{}{}", prefix, digits);
+        match e.kind() {
+            std::num::IntErrorKind::PosOverflow => LexerError::IntegerOverflow(literal),
+            _ => LexerError::MalformedNumber(literal)
+        }
+    })
+}
+
+/// Computes `value * 2^exp` like C's `ldexp`. The power is applied in
+/// steps that are exact, so only the last one rounds and results in the
+/// subnormal range such as `0x1p-1074` come out right.
+fn scale_by_pow2(mut value: f64, mut exp: i32) -> f64 {
+    // 2^exp for exp in -1022..=1023, built from its bits
+    let pow2 = |exp: i32| f64::from_bits(((exp + 1023) as u64) << 52);
+    while exp > 1000 && value.is_finite() && value != 0.0 {
+        value *= pow2(1000);
+        exp -= 1000;
+    }
+    while exp < -1000 && value != 0.0 {
+        value *= pow2(-1000);
+        exp += 1000;
+    }
+    value * pow2(exp.clamp(-1000, 1000))
+}
+
+fn parse_hex(input: &mut std::iter::Peekable<std::str::Chars>, min_digits: usize, max_digits: usize) -> Result<u32, LexerError> {
+    let mut code = 0u32;
+    let mut digits = 0;
+    while digits < max_digits {
+        match input.peek().and_then(|char| char.to_digit(16)) {
+            Some(digit) => {
+                input.next();
+                code = code * 16 + digit;
+                digits += 1;
+            }
+            None => break
+        }
+    }
+    if digits < min_digits {
+        return Err(LexerError::StringError);
+    }
+    Ok(code)
+}
+
+/// Parses the escape sequence following a backslash.
+fn parse_escape(input: &mut std::iter::Peekable<std::str::Chars>) -> Result<char, LexerError> {
+    let char = match input.next() {
+        Some('r') => '\r',
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('0') => '\0',
+        Some(char @ ('"' | '\'' | '\\')) => char,
+        Some('x') => {
+            // `\xHH`, a code point up to U+00FF, always two digits
+            let code = parse_hex(input, 2, 2)?;
+            char::from_u32(code).unwrap()
+        }
+        Some('u') => {
+            // `\u{XXXX}`, any Unicode scalar value; lone surrogates are rejected
+            if input.next_if_eq(&'{').is_none() {
+                return Err(LexerError::StringError);
+            }
+            let code = parse_hex(input, 1, 6)?;
+            if input.next_if_eq(&'}').is_none() {
+                return Err(LexerError::StringError);
+            }
+            char::from_u32(code).ok_or(LexerError::StringError)?
+        }
+        _ => return Err(LexerError::StringError)
+    };
+
+    Ok(char)
+}
+
+/// Removes the line break after the opening delimiter and the indentation
+/// common to all non-blank lines. If the closing delimiter sits on its own
+/// line, its indentation counts too and the text ends with a line break.
+fn strip_indent(text: &str) -> String {
+    let is_blank = |line: &str| line.chars().all(|char| char == ' ' || char == '\t');
+    let indent_of = |line: &str| line.chars().take_while(|&char| char == ' ' || char == '\t').count();
+
+    let text = text.strip_prefix('\n').unwrap_or(text);
+    let mut lines: Vec<_> = text.split('\n').collect();
+
+    let closing = match lines.len() > 1 && is_blank(lines[lines.len() - 1]) {
+        true => lines.pop(),
+        false => None
+    };
+
+    let indent = lines.iter()
+        .filter(|line| !is_blank(line))
+        .map(|line| indent_of(line))
+        .chain(closing.map(indent_of))
+        .min()
+        .unwrap_or(0);
+
+    let mut str = lines.iter()
+        .map(|line| line.chars().skip(indent.min(indent_of(line))).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if closing.is_some() {
+        str.push('\n');
+    }
+
+    str
+}
+
 impl<'a> Lexer<'a> {
     fn skip_line(&mut self) {
         if let Some('\r') = self.input.find(|&char| char == '\r' || char == '\n') {
@@ -53,22 +201,112 @@ impl<'a> Lexer<'a> {
         Token::Single(first)
     }
 
-    fn read_digits(&mut self, str: &mut String, radix: u32) {
-        loop {
-            match self.input.next_if(|char| char.is_digit(radix)) {
-                Some(char) => str.push(char),
-                None => break
+    /// Reads digits of `radix` into `str`, skipping `_` separators, which
+    /// are only allowed between two digits.
+    fn read_digits(&mut self, str: &mut String, radix: u32) -> Result<(), LexerError> {
+        let mut after_digit = str.chars().last().is_some_and(|char| char.is_digit(radix));
+
+        while let Some(char) = self.input.next_if(|&char| char.is_digit(radix) || char == '_') {
+            if char == '_' {
+                if !after_digit || !self.input.peek().is_some_and(|char| char.is_digit(radix)) {
+                    // report the rest of the literal too
+                    str.push('_');
+                    while let Some(char) = self.input.next_if(|&char| char.is_digit(radix) || char == '_') {
+                        str.push(char);
+                    }
+                    return Err(LexerError::MalformedNumber(str.clone()));
+                }
+                after_digit = false;
+            } else {
+                str.push(char);
+                after_digit = true;
             }
         }
+
+        Ok(())
     }
 
-    fn parse_integer(&mut self, radix: u32) -> Result<Token, LexerError> {
+    fn parse_integer(&mut self, prefix: &str, radix: u32) -> Result<Token, LexerError> {
         let mut str = String::new();
 
-        self.read_digits(&mut str, radix);
+        self.read_digits(&mut str, radix)?;
+
+        if let Some(&char) = self.input.peek().filter(|char| char.is_ascii_digit()) {
+            return Err(LexerError::MalformedNumber(format!("{}{}{}", prefix, str, char)));
+        }
+
+        to_integer(&str, radix, prefix)
+    }
+
+    fn parse_exponent(&mut self, str: &mut String) -> Result<(), LexerError> {
+        if let Some(char) = self.input.next_if(|&char| char == '+' || char == '-') {
+            str.push(char);
+        }
+
+        match self.input.next() {
+            Some('0') => str.push('0'),
+            Some(char @ '1'..='9') => {
+                str.push(char);
+                self.read_digits(str, 10)?;
+            }
+            _ => return Err(LexerError::MalformedNumber(str.clone()))
+        }
+
+        Ok(())
+    }
+
+    /// Parses a hexadecimal literal after `0x`: an integer, or a float with
+    /// a binary exponent such as `0x1.8p3`.
+    fn parse_hex(&mut self) -> Result<Token, LexerError> {
+        let mut str = String::from("0x");
+        self.read_digits(&mut str, 16)?;
+        let int_end = str.len();
+
+        if !matches!(self.input.peek(), Some('.' | 'P' | 'p')) {
+            return to_integer(&str[2..], 16, "0x");
+        }
+
+        let mut frac_start = int_end;
+        if self.input.next_if_eq(&'.').is_some() {
+            str.push('.');
+            frac_start += 1;
+            self.read_digits(&mut str, 16)?;
+        }
+        let frac_end = str.len();
+
+        if int_end == 2 && frac_end == frac_start {
+            return Err(LexerError::MalformedNumber(str));
+        }
+        match self.input.next_if(|&char| char == 'P' || char == 'p') {
+            Some(char) => str.push(char),
+            // a binary exponent is required, otherwise `0x1.8` would read as `0x18`
+            None => return Err(LexerError::MalformedNumber(str))
+        }
+
+        let exp_start = str.len();
+        self.parse_exponent(&mut str)?;
+        let mut exp: i32 = match str[exp_start..].parse() {
+            Ok(exp) => exp,
+            Err(_) => return Err(LexerError::MalformedNumber(str))
+        };
+
+        let mut mantissa = 0u64;
+        for (idx, char) in str[2..frac_end].char_indices() {
+            let is_frac = idx + 2 >= frac_start;
+            if char == '.' {
+                continue;
+            }
+            if mantissa >> 60 == 0 {
+                mantissa = mantissa * 16 + char.to_digit(16).unwrap() as u64;
+                if is_frac {
+                    exp = exp.saturating_sub(4);
+                }
+            } else if !is_frac {
+                exp = exp.saturating_add(4);
+            }
+        }
 
-        i64::from_str_radix(&str, radix)
-            .map_or(Err(LexerError::NumberError), |num| Ok(Token::Integer(num)))
+        Ok(Token::Float(scale_by_pow2(mantissa as f64, exp)))
     }
 
     fn parse_float(&mut self, first: char) -> Result<Token, LexerError> {
@@ -79,22 +317,22 @@ impl<'a> Lexer<'a> {
         if first == '.' {
             is_integer = false;
 
-            self.read_digits(&mut str, 10);
+            self.read_digits(&mut str, 10)?;
 
             if str.len() == 1 {
                 return Ok(Token::Single('.'));
             }
         } else {
             if first != '0' {
-                self.read_digits(&mut str, 10);
+                self.read_digits(&mut str, 10)?;
             }
 
-            if let Some(_) = self.input.next_if_eq(&'.') {
+            if self.input.next_if_eq(&'.').is_some() {
                 is_integer = false;
 
                 str.push('.');
 
-                self.read_digits(&mut str, 10);
+                self.read_digits(&mut str, 10)?;
             }
         }
 
@@ -103,62 +341,66 @@ impl<'a> Lexer<'a> {
 
             str.push(char);
 
-            if let Some(char) = self.input.next_if(|&char| char == '+' || char == '-') {
-                str.push(char);
-            }
-
-            match self.input.next() {
-                Some('0') => str.push('0'),
-                Some(char @ '1'..='9') => {
-                    str.push(char);
-                    self.read_digits(&mut str, 10);
-                }
-                _ => return Err(LexerError::NumberError)
-            }
+            self.parse_exponent(&mut str)?;
         }
 
         if is_integer {
-            i64::from_str_radix(&str, 10)
-                .map_or(Err(LexerError::NumberError), |num| Ok(Token::Integer(num)))
+            to_integer(&str, 10, "")
         } else {
             str.parse()
-                .map_or(Err(LexerError::NumberError), |num| Ok(Token::Float(num)))
+                .map_or(Err(LexerError::MalformedNumber(str)), |num| Ok(Token::Float(num)))
         }
     }
 
     fn parse_number(&mut self, first: char) -> Result<Token, LexerError> {
         match first {
             '0' => match self.input.peek() {
-                Some('0'..='7') => self.parse_integer(8),
+                Some('0'..='9') => {
+                    // legacy octal, e.g. `017`
+                    let token = self.parse_integer("0", 8)?;
+                    // a run of zeros such as `00` means the same in any base
+                    if let Token::Integer(num @ 1..) = token {
+                        self.warnings.push(LexerWarning::LegacyOctal(num));
+                    }
+                    Ok(token)
+                }
+                Some('O' | 'o') => {
+                    self.input.next();
+                    self.parse_integer("0o", 8)
+                }
                 Some('X' | 'x') => {
                     self.input.next();
-                    self.parse_integer(16)
+                    self.parse_hex()
                 },
                 Some('B' | 'b') => {
                     self.input.next();
-                    self.parse_integer(2)
+                    self.parse_integer("0b", 2)
                 },
                 Some('.' | 'E' | 'e') => self.parse_float(first),
+                // `0_1` is neither a legacy octal nor `0` followed by a name
+                Some('_') => Err(LexerError::MalformedNumber("0_".to_owned())),
                 _ => Ok(Token::Integer(0))
             }
             _ => self.parse_float(first)
         }
     }
 
-    fn parse_string(&mut self, first: char) -> Result<Token, LexerError> {
+    fn parse_string(&mut self, quote: char, raw: bool) -> Result<Token, LexerError> {
+        if self.input.next_if_eq(&quote).is_some() {
+            if self.input.next_if_eq(&quote).is_none() {
+                return Ok(Token::String(String::new()));
+            }
+            return self.parse_multiline_string(quote, raw);
+        }
+
         let mut str = String::new();
 
         loop {
             let char = match self.input.next() {
                 Some('\r' | '\n') | None => return Err(LexerError::StringError),
-                Some('\\') => match self.input.next() {
-                    Some('r') => '\r',
-                    Some('n') => '\n',
-                    Some(char @ ('"' | '\'' | '\\')) => char,
-                    _ => return Err(LexerError::StringError)
-                }
+                Some('\\') if !raw => parse_escape(&mut self.input)?,
                 Some(char) => {
-                    if char == first {
+                    if char == quote {
                         break;
                     }
                     char
@@ -171,22 +413,105 @@ impl<'a> Lexer<'a> {
         Ok(Token::String(str))
     }
 
-    fn parse_name(&mut self, first: char) -> Token {
-        let mut name = String::from(first);
+    /// Parses the body of a triple-quoted string, stripping the common
+    /// indentation before escapes are processed.
+    fn parse_multiline_string(&mut self, quote: char, raw: bool) -> Result<Token, LexerError> {
+        let mut text = String::new();
+        let mut quotes = 0;
 
         loop {
-            match self.input.next_if(|char|
-                ('A'..='Z').contains(char) ||
-                ('a'..='z').contains(char) ||
-                ('0'..='9').contains(char) ||
-                *char == '_'
-            ) {
-                Some(char) => name.push(char),
-                None => break
+            match self.input.next() {
+                None => return Err(LexerError::StringError),
+                Some(char) if char == quote => {
+                    quotes += 1;
+                    if quotes == 3 {
+                        text.truncate(text.len() - 2);
+                        break;
+                    }
+                    text.push(char);
+                    continue;
+                }
+                Some('\\') if !raw => {
+                    text.push('\\');
+                    text.push(self.input.next().ok_or(LexerError::StringError)?);
+                }
+                Some('\r') => {
+                    self.input.next_if_eq(&'\n');
+                    text.push('\n');
+                }
+                Some(char) => text.push(char)
+            }
+            quotes = 0;
+        }
+
+        let text = strip_indent(&text);
+
+        if raw {
+            return Ok(Token::String(text));
+        }
+
+        let mut input = text.chars().peekable();
+        let mut str = String::new();
+        while let Some(char) = input.next() {
+            match char {
+                '\\' => str.push(parse_escape(&mut input)?),
+                char => str.push(char)
             }
         }
 
-        Token::Name(name)
+        Ok(Token::String(str))
+    }
+
+    /// Parses template text up to the next `{` hole (`Template`) or the
+    /// closing backtick (`TemplateEnd`). `continued` tells whether this
+    /// text follows a hole rather than the opening backtick.
+    fn parse_template(&mut self, continued: bool) -> Result<Token, LexerError> {
+        let mut str = String::new();
+
+        loop {
+            let char = match self.input.next() {
+                None => return Err(LexerError::StringError),
+                Some('\\') => match self.input.peek() {
+                    Some(&char @ ('`' | '{' | '}')) => {
+                        self.input.next();
+                        char
+                    }
+                    _ => parse_escape(&mut self.input)?
+                }
+                Some('{') => {
+                    if !continued {
+                        self.holes.push(0);
+                    }
+                    return Ok(Token::Template(str));
+                }
+                Some('`') => {
+                    if continued {
+                        self.holes.pop();
+                    }
+                    return Ok(Token::TemplateEnd(str));
+                }
+                Some(char) => char
+            };
+
+            str.push(char);
+        }
+    }
+
+    /// Parses an identifier made of Unicode `XID_Start`/`XID_Continue`
+    /// characters (plus `_`), normalized to NFC so that canonically
+    /// equivalent spellings name the same variable or field.
+    fn parse_name(&mut self, first: char) -> Token {
+        let mut name = String::from(first);
+
+        while let Some(char) = self.input.next_if(|&char| char == '_' || char.is_xid_continue()) {
+            name.push(char);
+        }
+
+        if name.is_ascii() {
+            Token::Name(name)
+        } else {
+            Token::Name(name.nfc().collect())
+        }
     }
 
     fn parse_token(&mut self) -> Result<Token, LexerError> {
@@ -213,13 +538,39 @@ impl<'a> Lexer<'a> {
                         self.skip_multi_comment()?;
                         continue;
                     }
+                    Some('=') => {
+                        self.input.next();
+                        Token::OpAssign('/')
+                    }
                     _ => Token::Single('/')
                 }
                 ' ' | '\t' | '\r' | '\n' => continue,
                 '=' => self.check_double('=', '=', Token::Eq),
                 '!' => self.check_double('!', '=', Token::Ne),
-                '&' => self.check_double('&', '&', Token::And),
-                '|' => self.check_double('|', '|', Token::Or),
+                '&' | '|' => match self.input.peek() {
+                    Some(&next) if next == char => {
+                        self.input.next();
+                        if char == '&' { Token::And } else { Token::Or }
+                    }
+                    Some('=') => {
+                        self.input.next();
+                        Token::OpAssign(char)
+                    }
+                    _ => Token::Single(char)
+                }
+                '*' => match self.input.peek() {
+                    Some('*') => {
+                        self.input.next();
+                        Token::Pow
+                    }
+                    Some('=') => {
+                        self.input.next();
+                        Token::OpAssign('*')
+                    }
+                    _ => Token::Single('*')
+                }
+                '~' => self.check_double('~', '/', Token::IDiv),
+                '+' | '-' | '%' | '^' => self.check_double(char, '=', Token::OpAssign(char)),
                 '>' => match self.input.peek() {
                     Some('=') => {
                         self.input.next();
@@ -227,7 +578,10 @@ impl<'a> Lexer<'a> {
                     }
                     Some('>') => {
                         self.input.next();
-                        Token::Shr
+                        match self.input.next_if_eq(&'=') {
+                            Some(_) => Token::ShrAssign,
+                            None => Token::Shr
+                        }
                     }
                     _ => Token::Single('>')
                 }
@@ -238,16 +592,79 @@ impl<'a> Lexer<'a> {
                     }
                     Some('<') => {
                         self.input.next();
-                        Token::Shl
+                        match self.input.peek() {
+                            Some('=') => {
+                                self.input.next();
+                                Token::ShlAssign
+                            }
+                            Some('<') => {
+                                self.input.next();
+                                Token::OutRepr
+                            }
+                            _ => Token::Shl
+                        }
                     }
                     _ => Token::Single('<')
                 }
-                '(' | ')' | '{' | '}' | '[' | ']' | ';' | ',' | '?' | ':' |
-                '+' | '-' | '*' | '%' | '^' | '~' | '@' | '$'
+                '{' => {
+                    if let Some(depth) = self.holes.last_mut() {
+                        *depth += 1;
+                    }
+                    Token::Single('{')
+                }
+                '}' => match self.holes.last_mut() {
+                    Some(0) => self.parse_template(true)?,
+                    Some(depth) => {
+                        *depth -= 1;
+                        Token::Single('}')
+                    }
+                    None => Token::Single('}')
+                }
+                '`' => self.parse_template(false)?,
+                '?' => match self.input.peek() {
+                    Some('?') => {
+                        self.input.next();
+                        Token::Coalesce
+                    }
+                    Some('[') => {
+                        self.input.next();
+                        Token::OptIndex
+                    }
+                    Some('(') => {
+                        self.input.next();
+                        Token::OptCall
+                    }
+                    Some('.') => {
+                        // `c ?.5 : x` is still a conditional with a float
+                        let mut ahead = self.input.clone();
+                        ahead.next();
+                        match ahead.peek() {
+                            Some('0'..='9') => Token::Single('?'),
+                            _ => {
+                                self.input.next();
+                                Token::OptDot
+                            }
+                        }
+                    }
+                    _ => Token::Single('?')
+                }
+                '(' | ')' | '[' | ']' | ';' | ',' | ':' |
+                '@' | '$'
                     => Token::Single(char),
+                '.' if self.input.peek() == Some(&'.') => {
+                    self.input.next();
+                    if self.input.next_if_eq(&'.').is_none() {
+                        return Err(LexerError::CharacterError('.'));
+                    }
+                    Token::Ellipsis
+                }
                 '0'..='9' | '.' => self.parse_number(char)?,
-                '"' | '\'' => self.parse_string(char)?,
-                'A'..='Z' | 'a'..='z' | '_' => self.parse_name(char),
+                '"' | '\'' => self.parse_string(char, false)?,
+                'r' if matches!(self.input.peek(), Some('"' | '\'')) => {
+                    let quote = self.input.next().unwrap();
+                    self.parse_string(quote, true)?
+                }
+                char if char == '_' || char.is_xid_start() => self.parse_name(char),
                 char => return Err(LexerError::CharacterError(char))
             };
 
@@ -258,22 +675,55 @@ impl<'a> Lexer<'a> {
     pub fn new(input: std::str::Chars<'a>) -> Self {
         Self {
             input: input.peekable(),
-            peeked: None
+            peeked: None,
+            pending: vec![],
+            holes: vec![],
+            warnings: vec![]
         }
     }
 
+    pub fn take_warnings(&mut self) -> Vec<LexerWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
     pub fn next(&mut self) -> Result<Token, LexerError> {
-        match self.peeked.take() {
+        match self.peeked.take().or_else(|| self.pending.pop()) {
             Some(token) => Ok(token),
             None => self.parse_token()
         }
     }
 
     pub fn peek(&mut self) -> Result<&Token, LexerError> {
-        if let None = self.peeked {
-            self.peeked = Some(self.parse_token()?);
+        if self.peeked.is_none() {
+            self.peeked = match self.pending.pop() {
+                Some(token) => Some(token),
+                None => Some(self.parse_token()?)
+            };
         }
 
         Ok(self.peeked.as_ref().unwrap())
     }
+
+    /// Hands `tokens` back, so that they are returned again, in order,
+    /// before anything else.
+    pub fn unread(&mut self, tokens: Vec<Token>) {
+        if let Some(token) = self.peeked.take() {
+            self.pending.push(token);
+        }
+        self.pending.extend(tokens.into_iter().rev());
+    }
+
+    /// Splits a peeked `??`, `?[` or `?(` into `?` and the rest, for
+    /// places where `?` is the type operator rather than a postfix one.
+    pub fn split_question(&mut self) -> Result<(), LexerError> {
+        let rest = match self.peek()? {
+            Token::Coalesce => Token::Single('?'),
+            Token::OptIndex => Token::Single('['),
+            Token::OptCall => Token::Single('('),
+            _ => return Ok(())
+        };
+        self.peeked = None;
+        self.unread(vec![Token::Single('?'), rest]);
+        Ok(())
+    }
 }
@@ -3,11 +3,22 @@ pub mod parser;
 
 use std::str::Chars;
 use bytecode::program::{Program, ProgramBundle};
-use lexer::Lexer;
+use lexer::{Lexer, LexerWarning};
 use parser::ParserError;
 
+/// Version of the compiler, used to invalidate cached bytecode.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Source of the compiler itself, identifying its build for cached bytecode
+/// along with `VERSION`: any change to code generation invalidates it.
+pub const SOURCE: [&str; 3] = [include_str!("lib.rs"), include_str!("lexer.rs"), include_str!("parser.rs")];
+
 pub fn compile_chars(chars: Chars) -> Result<ProgramBundle, ParserError> {
+    Ok(compile_chars_with_warnings(chars)?.0)
+}
+
+pub fn compile_chars_with_warnings(chars: Chars) -> Result<(ProgramBundle, Vec<LexerWarning>), ParserError> {
     let mut program = Program::new();
-    parser::parse(Lexer::new(chars), &mut program)?;
-    Ok(program.bundle())
+    let warnings = parser::parse(Lexer::new(chars), &mut program)?;
+    Ok((program.bundle(), warnings))
 }
@@ -1,11 +1,13 @@
-use crate::lexer::{Lexer, Token, LexerError};
-use bytecode::{code, program::{Program, GeneratingError, JumpWhere}};
+use crate::lexer::{Lexer, Token, LexerError, LexerWarning};
+use bytecode::{code, program::{Program, GeneratingError, JumpWhere, Handler}};
 
 #[derive(Debug)]
 pub enum ParserError {
     LexerError(LexerError),
     UnexpectedToken(Token),
     NotLeftValue,
+    // `^` or `^^` without an enclosing loop (with that label)
+    NoSuchLoop(Option<String>),
     GeneratingError(GeneratingError)
 }
 
@@ -23,7 +25,33 @@ impl From<GeneratingError> for ParserError {
 
 struct Parser<'a, 'b> {
     lexer: Lexer<'a>,
-    program: &'b mut Program
+    program: &'b mut Program,
+    blocks: Vec<BlockInfo>,
+    // number of variable scopes around the code being compiled
+    scope_level: usize,
+    // `scope_level` of the body of each function being compiled
+    frames: Vec<usize>,
+    // number of conditionals whose `:` is still ahead in the current expression
+    open_branches: u32
+}
+
+enum BlockKind {
+    Block,
+    // with the loop's label, if any
+    Loop(Option<String>),
+    Try
+}
+
+/// A `:` loop, inline `{ }` block or `try` being compiled.
+struct BlockInfo {
+    kind: BlockKind,
+    // `Program::func_level` of the function containing it
+    func_level: usize,
+    // nesting depth among the loops and blocks of that function
+    depth: u8,
+    // code position its opening instruction continues at, telling it from
+    // other blocks at that depth
+    start: u16
 }
 
 #[derive(Debug)]
@@ -32,7 +60,24 @@ enum LeftValue {
     Super(String),
     Field(String),
     Item,
-    Slice
+    Slice,
+    Pattern(Pattern)
+}
+
+/// A destructuring pattern, e.g. `[a, {x, y: $b}, c = 0]`.
+#[derive(Debug)]
+enum Pattern {
+    // a `Variable` or `Super` left value
+    Target(Box<LeftValue>),
+    Array(Vec<PatternElem>),
+    Object(Vec<(String, PatternElem)>)
+}
+
+#[derive(Debug)]
+struct PatternElem {
+    pattern: Pattern,
+    // tokens of the default expression, used when the value is null
+    default: Vec<Token>
 }
 
 #[derive(Debug)]
@@ -42,7 +87,8 @@ enum UOpAction {
     Arg,
     Return,
     In,
-    Out,
+    // `OUT` or `OUT_REPR`
+    Out(u8),
     Code(u8)
 }
 
@@ -56,9 +102,12 @@ struct UOp {
 #[derive(Debug)]
 enum BOpAction {
     Assign,
+    // compound assignment, applying the code to the old value first
+    CompoundAssign(u8),
     Or,
     And,
     If,
+    Coalesce,
     Code(u8)
 }
 
@@ -121,7 +170,8 @@ impl<'a, 'b> Parser<'a, 'b> {
                 self.program.str(name)?;
             },
             LeftValue::Item => self.program.byte(code::LOAD_ITEM),
-            LeftValue::Slice => self.program.byte(code::LOAD_SLICE)
+            LeftValue::Slice => self.program.byte(code::LOAD_SLICE),
+            LeftValue::Pattern(_) => return Err(ParserError::NotLeftValue)
         }
         Ok(())
     }
@@ -151,16 +201,309 @@ impl<'a, 'b> Parser<'a, 'b> {
                 self.program.byte(code::DUP_PRE4);
                 self.program.byte(code::STORE_SLICE);
             }
+            LeftValue::Pattern(pattern) => self.destructure(pattern)?
         }
         Ok(())
     }
 
+    /// Stores the parts of the value on the stack into the targets of
+    /// `pattern`, leaving the value itself on the stack.
+    fn destructure(&mut self, pattern: &Pattern) -> Result<(), ParserError> {
+        match pattern {
+            Pattern::Target(lval) => self.write_left_value(lval)?,
+            Pattern::Array(elems) => for (idx, elem) in elems.iter().enumerate() {
+                // an element with a default may be missing, so it is loaded
+                // as null when the index is past the end
+                let jump1 = if elem.default.is_empty() {
+                    None
+                } else {
+                    self.program.byte(code::DUP);
+                    self.program.byte(code::LEN);
+                    self.program.push_int(idx as i64)?;
+                    self.program.byte(code::CMP_GT);
+                    self.program.byte(code::JF);
+                    Some(self.program.jump_where())
+                };
+                self.program.byte(code::DUP);
+                self.program.push_int(idx as i64)?;
+                self.program.byte(code::LOAD_ITEM);
+                if let Some(jump1) = jump1 {
+                    self.program.byte(code::JMP);
+                    let jump2 = self.program.jump_where();
+                    self.program.jump_here(jump1)?;
+                    self.program.byte(code::PUSH_NULL);
+                    self.program.jump_here(jump2)?;
+                }
+                self.destructure_elem(elem)?;
+            }
+            Pattern::Object(entries) => for (key, elem) in entries {
+                self.program.byte(code::DUP);
+                self.program.byte(code::LOAD_FIELD);
+                self.program.str(key)?;
+                self.destructure_elem(elem)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn destructure_elem(&mut self, elem: &PatternElem) -> Result<(), ParserError> {
+        if !elem.default.is_empty() {
+            // same as `value ?? default`
+            self.program.byte(code::DUP);
+            self.program.byte(code::JN);
+            let jump1 = self.program.jump_where();
+            self.program.byte(code::JMP);
+            let jump2 = self.program.jump_where();
+            self.program.jump_here(jump1)?;
+            self.program.byte(code::POP);
+
+            let mut tokens = elem.default.clone();
+            tokens.push(Token::EOF);
+            self.lexer.unread(tokens);
+            self.expression()?;
+            match self.lexer.next()? {
+                Token::EOF => {}
+                token => return Err(ParserError::UnexpectedToken(token))
+            }
+
+            self.program.jump_here(jump2)?;
+        }
+        self.destructure(&elem.pattern)?;
+        self.program.byte(code::POP);
+        Ok(())
+    }
+
+    fn take_token(&mut self, tokens: &mut Vec<Token>) -> Result<Token, ParserError> {
+        let token = self.lexer.next()?;
+        tokens.push(token.clone());
+        Ok(token)
+    }
+
+    /// Tries to read a destructuring pattern. Every token consumed is
+    /// recorded in `tokens`; `None` means the input is not a pattern.
+    fn scan_pattern(&mut self, first: Token, tokens: &mut Vec<Token>) -> Result<Option<Pattern>, ParserError> {
+        let closing = match first {
+            Token::Name(name) => return Ok(Some(Pattern::Target(Box::new(LeftValue::Variable(name))))),
+            Token::Single('$') => return match self.take_token(tokens)? {
+                Token::Name(name) => Ok(Some(Pattern::Target(Box::new(LeftValue::Super(name))))),
+                _ => Ok(None)
+            },
+            Token::Single('[') => Token::Single(']'),
+            Token::Single('{') => Token::Single('}'),
+            _ => return Ok(None)
+        };
+        let is_array = closing == Token::Single(']');
+
+        let mut elems = vec![];
+        let mut entries = vec![];
+
+        loop {
+            let token = self.take_token(tokens)?;
+
+            let (key, pattern) = if is_array {
+                match self.scan_pattern(token, tokens)? {
+                    Some(pattern) => (String::new(), pattern),
+                    None => return Ok(None)
+                }
+            } else {
+                let key = match token {
+                    Token::Name(key) => key,
+                    _ => return Ok(None)
+                };
+                if self.lexer.peek()? == &Token::Single(':') {
+                    self.take_token(tokens)?;
+                    let token = self.take_token(tokens)?;
+                    match self.scan_pattern(token, tokens)? {
+                        Some(pattern) => (key, pattern),
+                        None => return Ok(None)
+                    }
+                } else {
+                    let pattern = Pattern::Target(Box::new(LeftValue::Variable(key.clone())));
+                    (key, pattern)
+                }
+            };
+
+            let default = match self.scan_default(tokens)? {
+                Some(default) => default,
+                None => return Ok(None)
+            };
+
+            let elem = PatternElem { pattern, default };
+            if is_array {
+                elems.push(elem);
+            } else {
+                entries.push((key, elem));
+            }
+
+            match self.take_token(tokens)? {
+                Token::Single(',') => {}
+                token if token == closing => break,
+                _ => return Ok(None)
+            }
+        }
+
+        Ok(Some(if is_array {
+            Pattern::Array(elems)
+        } else {
+            Pattern::Object(entries)
+        }))
+    }
+
+    /// Collects the tokens of an `= default` up to the next `,`, `]` or `}`
+    /// outside brackets. An empty list means there is no default.
+    fn scan_default(&mut self, tokens: &mut Vec<Token>) -> Result<Option<Vec<Token>>, ParserError> {
+        let mut default = vec![];
+        if self.lexer.peek()? != &Token::Single('=') {
+            return Ok(Some(default));
+        }
+        self.take_token(tokens)?;
+
+        let mut depth = 0u32;
+        loop {
+            match self.lexer.peek()? {
+                Token::Single(',' | ']' | '}') if depth == 0 => break,
+                Token::Single(';' | ')') | Token::EOF if depth == 0 => return Ok(None),
+                Token::Single('(' | '[' | '{') | Token::OptIndex | Token::OptCall => depth += 1,
+                Token::Single(')' | ']' | '}') => depth -= 1,
+                _ => {}
+            }
+            default.push(self.take_token(tokens)?);
+        }
+
+        if default.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(default))
+    }
+
+    /// Reads a `[...]` or `{...}` pattern, restoring the lexer if it turns
+    /// out not to be one. With `assign`, the pattern must be followed by `=`.
+    fn try_pattern(&mut self, assign: bool) -> Result<Option<Pattern>, ParserError> {
+        let mut tokens = vec![];
+        let first = self.take_token(&mut tokens)?;
+        match self.scan_pattern(first, &mut tokens)? {
+            Some(pattern) if !assign || self.lexer.peek()? == &Token::Single('=') => Ok(Some(pattern)),
+            _ => {
+                self.lexer.unread(tokens);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Whether the peeked `??`, `?[` or `?(` after an operand is rather the
+    /// `?` of a conditional, as in `c ?(a) + 1 : b`: the case when a `:` for
+    /// it follows in the same expression, past those of enclosing conditionals.
+    fn is_conditional(&mut self) -> Result<bool, ParserError> {
+        let mut tokens = vec![];
+        let mut depth = match self.take_token(&mut tokens)? {
+            Token::OptIndex | Token::OptCall => 1u32,
+            _ => 0
+        };
+        // conditionals further on, still waiting for their `:`
+        let mut pending = 0u32;
+        let mut owed = self.open_branches;
+        let mut after_operand = false;
+        let found = loop {
+            let token = self.take_token(&mut tokens)?;
+            match &token {
+                Token::EOF => break false,
+                Token::Single('(' | '[' | '{') | Token::OptIndex | Token::OptCall => depth += 1,
+                Token::Single(')' | ']' | '}' | ';' | ',') if depth == 0 => break false,
+                Token::Single(')' | ']' | '}') => depth -= 1,
+                // a `:` after an operand is no loop
+                Token::Single('?') if depth == 0 && after_operand => pending += 1,
+                Token::Single(':') if depth == 0 && after_operand => match (pending, owed) {
+                    (0, 0) => break true,
+                    (0, _) => owed -= 1,
+                    _ => pending -= 1
+                }
+                _ => {}
+            }
+            after_operand = matches!(token,
+                Token::Name(_) | Token::Integer(_) | Token::Float(_) | Token::String(_) |
+                Token::TemplateEnd(_) | Token::Single(')' | ']' | '}'));
+        };
+        self.lexer.unread(tokens);
+        Ok(found)
+    }
+
+    /// Whether the `[...]` being parsed is the target of a compound
+    /// assignment, as in `arr[f()] += 1`, to be both read and written.
+    fn is_compound_target(&mut self) -> Result<bool, ParserError> {
+        let mut tokens = vec![];
+        let mut depth = 0u32;
+        let found = loop {
+            match self.take_token(&mut tokens)? {
+                Token::EOF => break false,
+                Token::Single('(' | '[' | '{') | Token::OptIndex | Token::OptCall => depth += 1,
+                Token::Single(')' | ']' | '}') if depth == 0 => break matches!(
+                    self.take_token(&mut tokens)?,
+                    Token::OpAssign(_) | Token::ShlAssign | Token::ShrAssign),
+                Token::Single(')' | ']' | '}') => depth -= 1,
+                _ => {}
+            }
+        };
+        self.lexer.unread(tokens);
+        Ok(found)
+    }
+
+    /// Duplicates the object of a field that is already on the stack, so
+    /// that it can be both read and written. Items and slices have their
+    /// operands duplicated as they are pushed instead.
+    fn dup_left_value(&mut self, lval: &LeftValue) {
+        if let LeftValue::Field(_) = lval {
+            self.program.byte(code::DUP);
+        }
+    }
+
     fn simple_expression(&mut self) -> Result<Option<LeftValue>, ParserError> {
+        if let Token::Single('[' | '{') = self.lexer.peek()? {
+            if let Some(pattern) = self.try_pattern(true)? {
+                return Ok(Some(LeftValue::Pattern(pattern)));
+            }
+        }
+
         let mut lval = match self.lexer.next()? {
+            Token::Single('^') => {
+                // `^` breaks out of a loop, `^^` continues it
+                let is_break = self.lexer.peek()? != &Token::Single('^');
+                if !is_break {
+                    self.lexer.next()?;
+                }
+                self.loop_exit(is_break)?;
+                return Ok(None);
+            }
             Token::Name(name) => Some(LeftValue::Variable(name)),
             Token::Integer(value) => { self.program.push_int(value)?; None },
             Token::Float(value) => { self.program.push_float(value)?; None },
             Token::String(value) => { self.program.push_str(&value)?; None },
+            Token::TemplateEnd(value) => { self.program.push_str(&value)?; None },
+            Token::Template(mut text) => {
+                // `text{expr}text{expr}...text`, concatenated by one `CONCAT`
+                let mut cnt = 0u8;
+                loop {
+                    if !text.is_empty() {
+                        self.program.push_str(&text)?;
+                        cnt = cnt.checked_add(1).ok_or(GeneratingError::ArgumentListExceeding)?;
+                    }
+                    self.expression()?;
+                    cnt = cnt.checked_add(1).ok_or(GeneratingError::ArgumentListExceeding)?;
+                    match self.lexer.next()? {
+                        Token::Template(next) => text = next,
+                        Token::TemplateEnd(last) => {
+                            if !last.is_empty() {
+                                self.program.push_str(&last)?;
+                                cnt = cnt.checked_add(1).ok_or(GeneratingError::ArgumentListExceeding)?;
+                            }
+                            break;
+                        }
+                        token => return Err(ParserError::UnexpectedToken(token))
+                    }
+                }
+                self.program.byte(code::CONCAT);
+                self.program.byte(cnt);
+                None
+            }
             Token::Single('$') => {
                 let mut level = 0u8;
                 while self.lexer.peek()? == &Token::Single('$') {
@@ -182,21 +525,47 @@ impl<'a, 'b> Parser<'a, 'b> {
                 None
             }
             Token::Single('{') => {
-                self.program.push_closure_and_switch()?;
-                self.statement_list(&Token::Single('}'))?;
-                self.program.switch_back();
-                self.program.byte(code::CALL);
-                self.program.byte(0);
+                self.block(None)?;
                 None
             }
             Token::Single('@') => match self.lexer.next()? {
+                Token::Single('<') => {
+                    self.function_exit()?;
+                    return Ok(None);
+                }
+                Token::Single('!') => {
+                    self.expression()?;
+                    self.program.byte(code::THROW);
+                    return Ok(None);
+                }
+                Token::Single('@') => {
+                    self.program.byte(code::PUSH_THIS);
+                    None
+                }
+                Token::Single('?') => {
+                    self.try_catch()?;
+                    None
+                }
                 Token::Single('{') => {
-                    self.program.push_closure_and_switch()?;
-                    self.statement_list(&Token::Single('}'))?;
-                    self.program.switch_back();
+                    self.function(false)?;
+                    None
+                }
+                Token::Single('(') => {
+                    self.function(true)?;
+                    None
+                }
+                Token::Name(mut name) => {
+                    // nested module name, e.g. `@util/strings`
+                    while self.lexer.peek()? == &Token::Single('/') {
+                        self.lexer.next()?;
+                        name.push('/');
+                        name.push_str(&self.expect_name()?);
+                    }
+                    self.program.byte(code::LOAD_LIB);
+                    self.program.str(&name)?;
                     None
                 }
-                Token::Name(name) | Token::String(name) => {
+                Token::String(name) => {
                     self.program.byte(code::LOAD_LIB);
                     self.program.str(&name)?;
                     None
@@ -209,16 +578,60 @@ impl<'a, 'b> Parser<'a, 'b> {
                 self.program.byte(cnt);
                 None
             }
+            Token::Ellipsis => {
+                // all arguments of the current function, none in a block
+                if self.in_block() {
+                    self.program.byte(code::NEW_ARRAY);
+                } else {
+                    self.program.byte(code::PUSH_ARGS);
+                }
+                self.program.byte(0);
+                None
+            }
             token => return Err(ParserError::UnexpectedToken(token))
         };
 
-        while let &Token::Single(char @ ('.' | '(' | '[')) = self.lexer.peek()? {
+        let mut null_jumps = vec![];
+        // whether `lval` is a field written `obj.name`, unlike `$$name`
+        let mut dotted = false;
+
+        loop {
+            if let Token::OptIndex | Token::OptCall = self.lexer.peek()? {
+                if self.is_conditional()? {
+                    self.lexer.split_question()?;
+                }
+            }
+            let (char, optional) = match self.lexer.peek()? {
+                &Token::Single(char @ ('.' | '(' | '[')) => (char, false),
+                Token::OptDot => ('.', true),
+                Token::OptIndex => ('[', true),
+                Token::OptCall => ('(', true),
+                _ => break
+            };
             self.lexer.next()?;
 
+            // `obj.f(...)` calls `f` with `obj` as its `@@`
+            if let (Some(LeftValue::Field(name)), '(', false, true) = (&lval, char, optional, dotted) {
+                let name = name.clone();
+                let cnt = self.expression_list(&Token::Single(')'))?;
+                self.program.byte(code::CALL_METHOD);
+                self.program.str(&name)?;
+                self.program.byte(cnt);
+                lval = None;
+                continue;
+            }
+
             if let Some(lval) = &lval {
                 self.read_left_value(lval)?;
             }
 
+            if optional {
+                // a null receiver skips the rest of the chain, which yields null
+                self.program.byte(code::DUP);
+                self.program.byte(code::JN);
+                null_jumps.push(self.program.jump_where());
+            }
+
             lval = match char {
                 '.' => {
                     let name = self.expect_name()?;
@@ -231,7 +644,9 @@ impl<'a, 'b> Parser<'a, 'b> {
                     None
                 }
                 '[' => {
-                    fn after_range(parser: &mut Parser) -> Result<Option<LeftValue>, ParserError> {
+                    // the operands of a compound assignment target are kept
+                    // for the write, duplicating each as it is pushed
+                    fn after_range(parser: &mut Parser, compound: bool) -> Result<Option<LeftValue>, ParserError> {
                         match parser.lexer.peek()? {
                             Token::Single(']') => {
                                 parser.lexer.next()?;
@@ -242,19 +657,32 @@ impl<'a, 'b> Parser<'a, 'b> {
                                 parser.expect_single(']')?;
                             }
                         }
+                        if compound {
+                            parser.program.byte(code::DUP_PRE3);
+                        }
                         Ok(Some(LeftValue::Slice))
                     }
 
+                    let compound = self.is_compound_target()?;
+                    if compound {
+                        self.program.byte(code::DUP);
+                    }
                     match self.lexer.peek()? {
                         Token::Single(':') => {
                             self.lexer.next()?;
                             self.program.byte(code::PUSH_NULL);
-                            after_range(self)?
+                            if compound {
+                                self.program.byte(code::DUP_PRE2);
+                            }
+                            after_range(self, compound)?
                         }
                         _ => {
                             self.expression()?;
+                            if compound {
+                                self.program.byte(code::DUP_PRE2);
+                            }
                             match self.lexer.next()? {
-                                Token::Single(':') => after_range(self)?,
+                                Token::Single(':') => after_range(self, compound)?,
                                 Token::Single(']') => Some(LeftValue::Item),
                                 token => return Err(ParserError::UnexpectedToken(token))
                             }
@@ -262,13 +690,213 @@ impl<'a, 'b> Parser<'a, 'b> {
                     }
                 }
                 _ => None
+            };
+            dotted = char == '.';
+        }
+
+        if !null_jumps.is_empty() {
+            // an optional chain is never a left value
+            if let Some(lval) = &lval {
+                self.read_left_value(lval)?;
+            }
+            lval = None;
+            for jump in null_jumps {
+                self.program.jump_here(jump)?;
             }
         }
 
         Ok(lval)
     }
 
+    /// Compiles the rest of a `{ }` block. It is called right away, so it is
+    /// compiled inline, with a scope of its own. With `bind`, the value on
+    /// top of the stack is stored to that name in the block's scope.
+    fn block(&mut self, bind: Option<&str>) -> Result<(), ParserError> {
+        self.program.byte(code::BLOCK);
+        let end = self.program.jump_where();
+        self.enter_block(BlockKind::Block)?;
+        self.scope_level += 1;
+        if let Some(name) = bind {
+            self.program.byte(code::DUP);
+            self.program.byte(code::STORE);
+            self.program.str(name)?;
+        }
+        self.statements(&Token::Single('}'))?;
+        self.program.byte(code::PUSH_SELF);
+        self.program.byte(code::END_BLOCK);
+        self.scope_level -= 1;
+        self.blocks.pop();
+        self.program.jump_here(end)?;
+        Ok(())
+    }
+
+    /// Compiles the rest of `@?{ } !e { } ~{ }`: code to try, code catching
+    /// its error as `e`, and code run after either. The `!` or the `~` part
+    /// may be left out, and so may the name of the error. Its value is that
+    /// of the block tried, or of the `!` block on an error.
+    fn try_catch(&mut self) -> Result<(), ParserError> {
+        self.program.byte(code::TRY);
+        let finally = self.program.jump_where();
+        let depth = self.enter_block(BlockKind::Try)?;
+
+        let start = self.program.get_pos();
+        self.expect_single('{')?;
+        self.block(None)?;
+        let end = self.program.get_pos();
+        self.program.byte(code::JMP);
+        let done = self.program.jump_where();
+
+        let mut handlers = vec![];
+        let mut catch = None;
+        if self.lexer.peek()? == &Token::Single('!') {
+            self.lexer.next()?;
+            let target = self.program.get_pos();
+            handlers.push(Handler { start, end, target, depth, cleanup: false });
+            // the error is on the stack, bound to the name inside the block
+            if let Token::Name(_) = self.lexer.peek()? {
+                let name = self.expect_name()?;
+                self.expect_single('{')?;
+                self.block(Some(&name))?;
+                self.program.byte(code::DUP_PRE2);
+                self.program.byte(code::POP);
+                self.program.byte(code::POP);
+            } else {
+                self.program.byte(code::POP);
+                self.expect_single('{')?;
+                self.block(None)?;
+            }
+            catch = Some((target, self.program.get_pos()));
+        }
+        self.program.jump_here(done)?;
+        self.program.byte(code::END_TRY);
+
+        if self.lexer.peek()? == &Token::Single('~') {
+            self.lexer.next()?;
+            self.program.jump_here(finally)?;
+            let target = self.program.get_pos();
+            handlers.push(Handler { start, end, target, depth, cleanup: true });
+            if let Some((start, end)) = catch {
+                handlers.push(Handler { start, end, target, depth, cleanup: true });
+            }
+            self.expect_single('{')?;
+            self.block(None)?;
+            self.program.byte(code::POP);
+            self.program.byte(code::END_FINALLY);
+        } else if catch.is_none() {
+            return Err(ParserError::UnexpectedToken(self.lexer.next()?));
+        }
+        // the `finally` code runs at the depth of the `try`
+        self.blocks.pop();
+
+        for handler in handlers {
+            self.program.add_handler(handler);
+        }
+        Ok(())
+    }
+
+    /// Reads the label of a loop written `:name: body`, if any.
+    fn loop_label(&mut self) -> Result<Option<String>, ParserError> {
+        if let Token::Name(_) = self.lexer.peek()? {
+            let name = self.lexer.next()?;
+            if self.lexer.peek()? == &Token::Single(':') {
+                self.lexer.next()?;
+                if let Token::Name(label) = name {
+                    return Ok(Some(label));
+                }
+            }
+            self.lexer.unread(vec![name]);
+        }
+        Ok(None)
+    }
+
+    /// Compiles the rest of `^ [:label] [value]`, breaking out of a loop, or
+    /// of `^^ [:label]`, continuing it. The loop may be in an enclosing
+    /// function, e.g. when written in a `:{ }` body.
+    fn loop_exit(&mut self, is_break: bool) -> Result<(), ParserError> {
+        let mut label = None;
+        if self.lexer.peek()? == &Token::Single(':') {
+            self.lexer.next()?;
+            label = Some(self.expect_name()?);
+        }
+
+        let target = self.blocks.iter().rev()
+            .find(|b| match &b.kind {
+                BlockKind::Loop(l) => label.is_none() || l == &label,
+                _ => false
+            })
+            .ok_or(ParserError::NoSuchLoop(label))?;
+        let (depth, start) = (target.depth, target.start);
+        // scopes to leave to get to the frame running the loop, if not this one
+        let level = match target.func_level == self.program.func_level() {
+            true => 0,
+            false => self.scope_level - self.frames[target.func_level - 1]
+        };
+        let level = level.try_into().map_err(|_| GeneratingError::JumpingTooFar)?;
+
+        if is_break {
+            self.optional_value()?;
+            self.exit_code(code::BREAK, level, depth, start);
+        } else {
+            self.exit_code(code::CONTINUE, level, depth, start);
+        }
+        Ok(())
+    }
+
+    /// Emits `BREAK` or `CONTINUE` for the block at `depth` and `start` of
+    /// the function `level` scopes out.
+    fn exit_code(&mut self, code: u8, level: u8, depth: u8, start: u16) {
+        self.program.byte(code);
+        self.program.byte(level);
+        self.program.byte(depth);
+        for byte in start.to_le_bytes() {
+            self.program.byte(byte);
+        }
+    }
+
+    /// Compiles the rest of `@< [value]`, which returns from the enclosing
+    /// `@` function even when written inside `{ }` blocks.
+    fn function_exit(&mut self) -> Result<(), ParserError> {
+        // blocks are inline, so this is always the current frame
+        self.optional_value()?;
+        self.program.byte(code::RETURN);
+        Ok(())
+    }
+
+    fn enter_block(&mut self, kind: BlockKind) -> Result<u8, ParserError> {
+        let func_level = self.program.func_level();
+        let depth = self.blocks.iter().filter(|b| b.func_level == func_level).count();
+        let depth = depth.try_into().map_err(|_| GeneratingError::JumpingTooFar)?;
+        let start = self.program.get_pos().try_into().map_err(|_| GeneratingError::JumpingTooFar)?;
+        self.blocks.push(BlockInfo { kind, func_level, depth, start });
+        Ok(depth)
+    }
+
+    /// The innermost `{ }` block of the current function, if any.
+    fn current_block(&self) -> Option<&BlockInfo> {
+        let func_level = self.program.func_level();
+        self.blocks.iter().rev()
+            .take_while(|b| b.func_level == func_level)
+            .find(|b| matches!(b.kind, BlockKind::Block))
+    }
+
+    fn in_block(&self) -> bool {
+        self.current_block().is_some()
+    }
+
+    /// Compiles the value after `^` or `@<`, null if left out.
+    fn optional_value(&mut self) -> Result<(), ParserError> {
+        match self.lexer.peek()? {
+            Token::Single(';' | ',' | ':' | ')' | ']' | '}') | Token::EOF =>
+                self.program.byte(code::PUSH_NULL),
+            _ => self.expression()?
+        }
+        Ok(())
+    }
+
     fn try_uop(&mut self) -> Result<Option<UOp>, ParserError> {
+        // e.g. `?[]` is the type of an array, not an optional index
+        self.lexer.split_question()?;
+
         let uop = match self.lexer.peek()? {
             Token::Single(char) => match char {
                 '+' => Some(UOp { pri: 16, action: UOpAction::NoOp, write_lval: false }),
@@ -283,7 +911,8 @@ impl<'a, 'b> Parser<'a, 'b> {
                 _ => None
             }
             Token::Shr => Some(UOp { pri: 0, action: UOpAction::In, write_lval: true }),
-            Token::Shl => Some(UOp { pri: 0, action: UOpAction::Out, write_lval: false }),
+            Token::Shl => Some(UOp { pri: 0, action: UOpAction::Out(code::OUT), write_lval: false }),
+            Token::OutRepr => Some(UOp { pri: 0, action: UOpAction::Out(code::OUT_REPR), write_lval: false }),
             _ => None
         };
 
@@ -291,6 +920,9 @@ impl<'a, 'b> Parser<'a, 'b> {
     }
 
     fn try_bop(&mut self) -> Result<Option<BOp>, ParserError> {
+        if self.lexer.peek()? == &Token::Coalesce && self.is_conditional()? {
+            self.lexer.split_question()?;
+        }
         let bop = match self.lexer.peek()? {
             Token::Single(char) => match char {
                 '+' => Some(BOp::left_c(14, code::ADD)),
@@ -307,12 +939,32 @@ impl<'a, 'b> Parser<'a, 'b> {
                 '=' => Some(BOp::right(1, BOpAction::Assign)),
                 _ => None
             },
+            Token::OpAssign(char) => {
+                let code = match char {
+                    '+' => code::ADD,
+                    '-' => code::SUB,
+                    '*' => code::MUL,
+                    '/' => code::DIV,
+                    '%' => code::MOD,
+                    '&' => code::BAND,
+                    '|' => code::BOR,
+                    '^' => code::BXOR,
+                    _ => return Ok(None)
+                };
+                Some(BOp::right(1, BOpAction::CompoundAssign(code)))
+            }
+            Token::ShlAssign => Some(BOp::right(1, BOpAction::CompoundAssign(code::SHL))),
+            Token::ShrAssign => Some(BOp::right(1, BOpAction::CompoundAssign(code::SHR))),
+            // binds tighter than unary operators: `-2 ** 2` is `-(2 ** 2)`
+            Token::Pow => Some(BOp::right(17, BOpAction::Code(code::POW))),
+            Token::IDiv => Some(BOp::left_c(15, code::IDIV)),
             Token::Eq => Some(BOp::left_c(7, code::CMP_EQ)),
             Token::Ne => Some(BOp::left_c(7, code::CMP_NE)),
             Token::Ge => Some(BOp::left_c(7, code::CMP_GE)),
             Token::Le => Some(BOp::left_c(7, code::CMP_LE)),
             Token::Shl => Some(BOp::left_c(11, code::SHL)),
             Token::Shr => Some(BOp::left_c(11, code::SHR)),
+            Token::Coalesce => Some(BOp::right(6, BOpAction::Coalesce)),
             Token::Or => Some(BOp::left(4, BOpAction::Or)),
             Token::And => Some(BOp::left(5, BOpAction::And)),
             _ => None
@@ -327,14 +979,28 @@ impl<'a, 'b> Parser<'a, 'b> {
                 self.lexer.next()?;
 
                 let mut pos = 0usize;
+                let mut loop_end = None;
 
                 if let UOpAction::Loop = uop.action {
+                    let label = self.loop_label()?;
                     self.program.byte(code::PUSH_NULL);
+                    self.program.byte(code::LOOP);
+                    loop_end = Some(self.program.jump_where());
                     pos = self.program.get_pos();
+                    self.enter_block(BlockKind::Loop(label))?;
                     self.program.byte(code::POP);
                 }
 
-                let (lval, _) = self.op_expression(uop.pri)?;
+                let mut lval = None;
+                if uop.write_lval {
+                    // e.g. `> [x, y];` destructures the argument
+                    if let Token::Single('[' | '{') = self.lexer.peek()? {
+                        lval = self.try_pattern(false)?.map(LeftValue::Pattern);
+                    }
+                }
+                if lval.is_none() {
+                    lval = self.op_expression(uop.pri)?.0;
+                }
 
                 if !uop.write_lval {
                     if let Some(lval) = &lval {
@@ -348,13 +1014,25 @@ impl<'a, 'b> Parser<'a, 'b> {
                         self.program.byte(code::DUP);
                         self.program.byte(code::JN);
                         self.program.jump_back(pos)?;
+                        self.program.byte(code::END_LOOP);
+                        self.program.jump_here(loop_end.take().unwrap())?;
+                        self.blocks.pop();
                     },
+                    // blocks used to be closures called without arguments
+                    UOpAction::Arg if self.in_block() => self.program.byte(code::PUSH_NULL),
                     UOpAction::Arg => self.program.push_arg()?,
-                    UOpAction::Return => self.program.byte(code::RETURN),
+                    // `<` in a block leaves the block with the value
+                    UOpAction::Return => match self.current_block() {
+                        Some(block) => {
+                            let (depth, start) = (block.depth, block.start);
+                            self.exit_code(code::BREAK, 0, depth, start);
+                        }
+                        None => self.program.byte(code::RETURN)
+                    }
                     UOpAction::In => self.program.byte(code::IN),
-                    UOpAction::Out => {
+                    UOpAction::Out(code) => {
                         self.program.byte(code::DUP);
-                        self.program.byte(code::OUT);
+                        self.program.byte(code);
                     }
                     UOpAction::Code(code) => self.program.byte(code)
                 }
@@ -383,6 +1061,13 @@ impl<'a, 'b> Parser<'a, 'b> {
 
             match bop.action {
                 BOpAction::Assign => {}
+                BOpAction::CompoundAssign(_) => match &lval {
+                    Some(lval) => {
+                        self.dup_left_value(lval);
+                        self.read_left_value(lval)?;
+                    }
+                    None => return Err(ParserError::NotLeftValue)
+                }
                 _ => if let Some(lval) = &lval {
                     self.read_left_value(lval)?;
                 }
@@ -400,10 +1085,19 @@ impl<'a, 'b> Parser<'a, 'b> {
                     jump = Some(self.program.jump_where());
                     self.program.byte(code::POP);
                 }
+                BOpAction::Coalesce => {
+                    self.program.byte(code::DUP);
+                    self.program.byte(code::JN);
+                    let jump1 = self.program.jump_where();
+                    self.program.byte(code::JMP);
+                    jump = Some(self.program.jump_where());
+                    self.program.jump_here(jump1)?;
+                    self.program.byte(code::POP);
+                }
                 BOpAction::If => {
                     self.program.byte(code::JF);
                     let jump1 = self.program.jump_where();
-                    self.expression()?;
+                    self.nested_expression(self.open_branches + 1)?;
                     self.expect_single(':')?;
                     self.program.byte(code::JMP);
                     jump = Some(self.program.jump_where());
@@ -423,7 +1117,11 @@ impl<'a, 'b> Parser<'a, 'b> {
                     Some(lval) => self.write_left_value(lval)?,
                     None => return Err(ParserError::NotLeftValue)
                 }
-                BOpAction::Or | BOpAction::And | BOpAction::If =>
+                BOpAction::CompoundAssign(code) => {
+                    self.program.byte(code);
+                    self.write_left_value(lval.as_ref().unwrap())?;
+                }
+                BOpAction::Or | BOpAction::And | BOpAction::If | BOpAction::Coalesce =>
                     self.program.jump_here(jump.unwrap())?,
                 BOpAction::Code(code) =>
                     self.program.byte(code)
@@ -437,12 +1135,19 @@ impl<'a, 'b> Parser<'a, 'b> {
     }
 
     fn expression(&mut self) -> Result<(), ParserError> {
+        self.nested_expression(0)
+    }
+
+    /// Parses an expression followed by the `:` of `open_branches` conditionals.
+    fn nested_expression(&mut self, open_branches: u32) -> Result<(), ParserError> {
+        let outer = std::mem::replace(&mut self.open_branches, open_branches);
         let (lval, _) = self.op_expression(0)?;
 
         if let Some(lval) = &lval {
             self.read_left_value(lval)?;
         }
 
+        self.open_branches = outer;
         Ok(())
     }
 
@@ -468,13 +1173,88 @@ impl<'a, 'b> Parser<'a, 'b> {
         Ok(cnt)
     }
 
-    fn statement_list(&mut self, ending: &Token) -> Result<(), ParserError> {
+    /// Compiles `x, y = 0, ...rest)` into argument bindings, as if written
+    /// `> x; > y; ...` at the start of the function body.
+    fn parameter_list(&mut self) -> Result<(), ParserError> {
+        let mut cnt = 0u8;
+
+        if self.lexer.peek()? != &Token::Single(')') {
+            loop {
+                if self.lexer.peek()? == &Token::Ellipsis {
+                    // any number of extra arguments is fine
+                    self.lexer.next()?;
+                    let name = self.expect_name()?;
+                    self.program.push_rest_args()?;
+                    self.program.byte(code::STORE);
+                    self.program.str(&name)?;
+                    self.expect_single(')')?;
+                    return Ok(());
+                }
+
+                let name = self.expect_name()?;
+                self.program.push_arg()?;
+                cnt += 1;
+
+                if self.lexer.peek()? == &Token::Single('=') {
+                    self.lexer.next()?;
+                    self.program.byte(code::DUP);
+                    self.program.byte(code::JN);
+                    let jump1 = self.program.jump_where();
+                    self.program.byte(code::JMP);
+                    let jump2 = self.program.jump_where();
+                    self.program.jump_here(jump1)?;
+                    self.program.byte(code::POP);
+                    self.expression()?;
+                    self.program.jump_here(jump2)?;
+                }
+
+                self.program.byte(code::STORE);
+                self.program.str(&name)?;
+
+                match self.lexer.peek()? {
+                    Token::Single(',') => {
+                        self.lexer.next()?;
+                    }
+                    _ => break
+                }
+            }
+        }
+        self.expect_single(')')?;
+
+        self.program.byte(code::CHECK_ARGS);
+        self.program.byte(cnt);
+        Ok(())
+    }
+
+    /// Compiles a function literal after `@`, with a parameter list if
+    /// `params` is set.
+    fn function(&mut self, params: bool) -> Result<(), ParserError> {
+        self.program.push_closure_and_switch()?;
+        self.scope_level += 1;
+        self.frames.push(self.scope_level);
+        if params {
+            self.parameter_list()?;
+            self.expect_single('{')?;
+        }
+        self.statement_list(&Token::Single('}'))?;
+        self.frames.pop();
+        self.scope_level -= 1;
+        self.program.switch_back();
+        Ok(())
+    }
+
+    fn statements(&mut self, ending: &Token) -> Result<(), ParserError> {
         while self.lexer.peek()? != ending {
             self.expression()?;
             self.expect_single(';')?;
             self.program.byte(code::POP);
         }
         self.lexer.next()?;
+        Ok(())
+    }
+
+    fn statement_list(&mut self, ending: &Token) -> Result<(), ParserError> {
+        self.statements(ending)?;
 
         self.program.byte(code::PUSH_SELF);
         self.program.byte(code::RETURN);
@@ -483,7 +1263,16 @@ impl<'a, 'b> Parser<'a, 'b> {
     }
 }
 
-pub fn parse(lexer: Lexer, program: &mut Program) -> Result<(), ParserError> {
-    let mut parser = Parser { lexer, program };
-    parser.statement_list(&Token::EOF)
+/// Parses a whole program, returning the warnings collected while lexing.
+pub fn parse(lexer: Lexer, program: &mut Program) -> Result<Vec<LexerWarning>, ParserError> {
+    let mut parser = Parser {
+        lexer,
+        program,
+        blocks: vec![],
+        scope_level: 1,
+        frames: vec![1],
+        open_branches: 0
+    };
+    parser.statement_list(&Token::EOF)?;
+    Ok(parser.lexer.take_warnings())
 }
@@ -1,36 +1,111 @@
-use std::{process::ExitCode, env, io, fs};
-use vm::executor;
+use std::{process::ExitCode, env, io, fs, path::PathBuf};
+use vm::{executor, types::{Context, Sandbox}, cache::CompileCache};
 
 fn main() -> ExitCode {
     let args: Vec<_> = env::args().collect();
 
-    let (source, path) = match args.len() {
-        1 => (
+    let mut lib_paths = vec![];
+    let mut file = None;
+    let mut use_cache = true;
+    let mut clear_cache = false;
+    let mut sandboxed = false;
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if let Some(dir) = arg.strip_prefix("-I") {
+            let dir = match dir {
+                "" => match iter.next() {
+                    Some(dir) => dir,
+                    None => {
+                        eprintln!("Missing directory after `-I`");
+                        return ExitCode::from(1);
+                    }
+                }
+                dir => dir
+            };
+            lib_paths.push(PathBuf::from(dir));
+        } else if arg == "--no-cache" {
+            use_cache = false;
+        } else if arg == "--clear-cache" {
+            clear_cache = true;
+        } else if arg == "--sandbox" {
+            sandboxed = true;
+        } else if file.is_none() {
+            file = Some(arg);
+        } else {
+            eprintln!("Usage: {} [-I dir]... [--no-cache] [--clear-cache] [--sandbox] [file]", args[0]);
+            return ExitCode::from(1);
+        }
+    }
+
+    let cache = CompileCache::default_dir().map(CompileCache::new);
+
+    if clear_cache {
+        if let Some(cache) = &cache {
+            if let Err(e) = cache.clear() {
+                eprintln!("Error clearing cache {:?}: {}", cache.dir(), e);
+                return ExitCode::from(1);
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let (source, path) = match file {
+        None => (
             // read from stdin
             io::read_to_string(io::stdin()).unwrap(),
             None
         ),
-        2 => (
+        Some(file) => (
             // read from file
-            fs::read_to_string(&args[1]).unwrap(),
-            Some(fs::canonicalize(&args[1]).unwrap().into())
-        ),
-        _ => {
-            eprintln!("Usage: {} [file]", args[0]);
-            return ExitCode::from(1);
-        }
+            fs::read_to_string(file).unwrap(),
+            Some(fs::canonicalize(file).unwrap().into())
+        )
     };
     eprintln!("Path: {:?}", path);
     eprintln!();
     eprintln!();
 
-    let program = compiler::compile_chars(source.chars()).unwrap();
+    let (program, warnings) = match compiler::compile_chars_with_warnings(source.chars()) {
+        Ok(compiled) => compiled,
+        Err(e) => {
+            eprintln!("Error compiling script: {:?}", e);
+            return ExitCode::from(1);
+        }
+    };
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
     program.print();
 
-    match executor::execute_program(program, path) {
+    let mut ctx = Context::new(program, path);
+    if use_cache {
+        ctx.set_cache(cache);
+    }
+    if sandboxed {
+        // only file libraries under `-I` directories, no built-in ones
+        let mut sandbox = Sandbox::restricted();
+        sandbox.set_io_allowed(true);
+        for dir in &lib_paths {
+            if let Err(e) = sandbox.add_lib_dir(dir) {
+                eprintln!("Error adding library directory {:?}: {}", dir, e);
+                return ExitCode::from(1);
+            }
+        }
+        ctx.set_sandbox(sandbox);
+    }
+    for dir in lib_paths.into_iter().chain(executor::default_lib_paths()) {
+        ctx.add_lib_path(dir);
+    }
+
+    let result = executor::execute_main(&mut ctx);
+    for warning in ctx.take_warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    match result {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
-            eprintln!("Error executing script: {:?}", e);
+            eprintln!("Error executing script: {}", e);
             ExitCode::from(1)
         }
     }
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use gc::Gc;
+use crate::types::{Context, Lockable, MapKey, Object, Value, VMError};
+
+/// Adds the libraries built into the VM, e.g. `@proto`, to a new `Context`.
+/// A file library of the same name takes their place.
+pub fn register(ctx: &mut Context) {
+    ctx.add_builtin_lib("proto".into(), proto_lib());
+    ctx.add_builtin_lib("value".into(), value_lib());
+    ctx.add_builtin_lib("map".into(), map_lib());
+}
+
+/// `@proto.get(obj)`, and `@proto.set(obj, proto)` which returns `obj`.
+/// A null prototype removes the link.
+fn proto_lib() -> Value {
+    Value::new_locked_obj(HashMap::from([
+        ("get".into(), Value::NativeFunction(|_, _, args| {
+            let [obj] = Value::extract_args("proto.get", args)?;
+            let obj = obj.as_obj().map_err(|e| e.at_arg("proto.get", 0))?;
+            Ok(obj.proto().map_or(Value::Null, Value::Object))
+        })),
+        ("set".into(), Value::NativeFunction(|_, _, args| {
+            let [obj, proto] = Value::extract_args("proto.set", args)?;
+            let proto = match &proto {
+                Value::Null => None,
+                Value::Object(proto) => Some(proto.clone()),
+                v => return Err(VMError::invalid_type("object/null", v).at_arg("proto.set", 1))
+            };
+            Object::set_proto(obj.as_obj().map_err(|e| e.at_arg("proto.set", 0))?, proto)?;
+            Ok(obj)
+        }))
+    ]))
+}
+
+/// `@value.equal(a, b)` compares by contents, see `Value::deep_eq`.
+/// `@value.lock(v)` gives a locked copy of an array, object or map, which
+/// may then be a map key if `@value.hashable(v)`.
+fn value_lib() -> Value {
+    Value::new_locked_obj(HashMap::from([
+        ("equal".into(), Value::NativeFunction(|_, _, args| {
+            let [v1, v2] = Value::extract_args("value.equal", args)?;
+            Ok(Value::Bool(v1.deep_eq(&v2)))
+        })),
+        ("lock".into(), Value::NativeFunction(|_, _, args| {
+            let [value] = Value::extract_args("value.lock", args)?;
+            Ok(match &value {
+                Value::Array(a) if !a.is_locked() => Value::new_locked_arr(a.get().clone()),
+                Value::Object(o) if !o.is_locked() => Value::Object(Gc::new(o.locked_copy())),
+                Value::Map(m) if !m.is_locked() => Value::Map(Gc::new(Lockable::new(m.get().clone(), true))),
+                Value::Array(_) | Value::Object(_) | Value::Map(_) => value,
+                v => return Err(VMError::invalid_type("array/object/map", v).at_arg("value.lock", 0))
+            })
+        })),
+        ("hashable".into(), Value::NativeFunction(|_, _, args| {
+            let [value] = Value::extract_args("value.hashable", args)?;
+            Ok(Value::Bool(MapKey::new(value).is_ok()))
+        }))
+    ]))
+}
+
+/// `@map.new()` makes an empty map, indexed like `m[key]` with any
+/// hashable key. `has`, `remove` and `keys` take the map first.
+fn map_lib() -> Value {
+    Value::new_locked_obj(HashMap::from([
+        ("new".into(), Value::NativeFunction(|_, _, args| {
+            let [] = Value::extract_args("map.new", args)?;
+            Ok(Value::new_map())
+        })),
+        ("has".into(), Value::NativeFunction(|_, _, args| {
+            let [map, key] = Value::extract_args("map.has", args)?;
+            let map = map.as_map().map_err(|e| e.at_arg("map.has", 0))?;
+            let has = map.get().contains_key(&MapKey::new(key)?);
+            Ok(Value::Bool(has))
+        })),
+        ("remove".into(), Value::NativeFunction(|_, _, args| {
+            let [map, key] = Value::extract_args("map.remove", args)?;
+            let map = map.as_map().map_err(|e| e.at_arg("map.remove", 0))?;
+            let removed = map.get_mut()?.remove(&MapKey::new(key)?);
+            Ok(removed.unwrap_or(Value::Null))
+        })),
+        ("keys".into(), Value::NativeFunction(|_, _, args| {
+            let [map] = Value::extract_args("map.keys", args)?;
+            let map = map.as_map().map_err(|e| e.at_arg("map.keys", 0))?;
+            let keys = map.get().keys().map(|k| k.value().clone()).collect();
+            Ok(Value::new_arr(keys))
+        }))
+    ]))
+}
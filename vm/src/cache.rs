@@ -0,0 +1,98 @@
+use std::{env, fs, io, path::{Path, PathBuf}};
+use bytecode::{program::{ProgramBundle, BUNDLE_FORMAT}, code::CODE_INFO};
+
+const CACHE_EXT: &str = "cutec";
+
+/// On-disk cache of compiled library bundles, keyed by a hash of the
+/// source text, the compiler build and the instruction set.
+pub struct CompileCache {
+    dir: PathBuf
+}
+
+impl CompileCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// `CUTE_CACHE_DIR` if set, otherwise `~/.cute/cache`.
+    pub fn default_dir() -> Option<PathBuf> {
+        if let Some(dir) = env::var_os("CUTE_CACHE_DIR") {
+            return Some(dir.into());
+        }
+        env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".cute").join("cache"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, source: &str) -> PathBuf {
+        let mut hash = Fnv1a::new();
+        hash.write(compiler::VERSION.as_bytes());
+        for source in compiler::SOURCE {
+            hash.write(source.as_bytes());
+            hash.write(&[0]);
+        }
+        hash.write(&[0, BUNDLE_FORMAT]);
+        // the opcode table too, in case a change forgot to bump the format
+        for info in CODE_INFO {
+            hash.write(info.name.as_bytes());
+            hash.write(&info.params.to_le_bytes());
+        }
+        hash.write(source.as_bytes());
+        self.dir.join(format!("{:016x}.{}", hash.finish(), CACHE_EXT))
+    }
+
+    /// Returns the cached bundle for `source`; unreadable or corrupt entries count as misses.
+    pub fn load(&self, source: &str) -> Option<ProgramBundle> {
+        let data = fs::read(self.entry_path(source)).ok()?;
+        ProgramBundle::from_bytes(&data).ok()
+    }
+
+    pub fn store(&self, source: &str, program: &ProgramBundle) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // write to a temporary file first so that readers never see half an entry
+        let path = self.entry_path(source);
+        let tmp_path = path.with_extension(format!("{}.tmp", CACHE_EXT));
+        fs::write(&tmp_path, program.to_bytes())?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Removes every cache entry, leaving unrelated files alone.
+    pub fn clear(&self) -> io::Result<()> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e)
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == CACHE_EXT) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 64-bit FNV-1a, chosen over `DefaultHasher` because its output is stable
+/// across Rust releases.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
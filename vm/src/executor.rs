@@ -1,6 +1,7 @@
-use std::{str::FromStr, rc::Rc, path::{Path, PathBuf}, fs};
+use std::{str::FromStr, rc::Rc, path::{Path, PathBuf}, fs, env};
 use bytecode::{program::{ProgramBundle, Constant}, code};
-use crate::types::{VMError, Variables, VMString, Closure, Value, Context, ProgramState};
+use gc::Gc;
+use crate::{types::{VMError, Variables, VMString, Closure, Value, MapKey, Context, ProgramState, Unwind, VMWarning}, manifest::Manifest};
 
 fn next(func: &Vec<u8>, pc: &mut usize) -> Result<u8, VMError> {
     let code = *func.get(*pc)
@@ -19,9 +20,14 @@ fn next_str<'a>(func: &Vec<u8>, pc: &mut usize, program: &'a ProgramBundle) -> R
     }
 }
 
-fn jump(pc: &mut usize, offset: u8) -> Result<(), VMError> {
-    *pc -= 1;
-    *pc = pc.checked_add_signed((offset as i8).into())
+fn next_offset(func: &Vec<u8>, pc: &mut usize) -> Result<i16, VMError> {
+    Ok(i16::from_le_bytes([next(func, pc)?, next(func, pc)?]))
+}
+
+/// Jumps relative to the start of the offset just read.
+fn jump(pc: &mut usize, offset: i16) -> Result<(), VMError> {
+    *pc -= 2;
+    *pc = pc.checked_add_signed(offset.into())
         .ok_or(VMError::PCIndexOutOfBound)?;
     Ok(())
 }
@@ -43,49 +49,401 @@ fn stack_pop(stack: &mut Vec<Value>) -> Result<Value, VMError> {
     stack.pop().ok_or(VMError::BadStack)
 }
 
+/// Applies an arithmetic operator to `v1` in place. Two ints use `int_op`;
+/// if either operand is a float, the other is promoted to float and
+/// `float_op` is used. Returns `false` if `v1` is not a number at all.
+fn numeric_op(
+    v1: &mut Value,
+    v2: &Value,
+    int_op: impl FnOnce(i64, i64) -> Result<Value, VMError>,
+    float_op: impl FnOnce(f64, f64) -> f64
+) -> Result<bool, VMError> {
+    *v1 = match (&*v1, v2) {
+        (Value::Int(a), Value::Int(b)) => int_op(*a, *b)?,
+        (Value::Int(a), Value::Float(b)) => Value::Float(float_op(*a as f64, *b)),
+        (Value::Float(a), Value::Int(b)) => Value::Float(float_op(*a, *b as f64)),
+        (Value::Float(a), Value::Float(b)) => Value::Float(float_op(*a, *b)),
+        (Value::Int(_) | Value::Float(_), _) => return Err(VMError::invalid_type("int/float", v2)),
+        _ => return Ok(false)
+    };
+    Ok(true)
+}
+
+/// Wrapping integer power for exponents beyond `u32`.
+fn int_pow(mut base: i64, mut exp: i64) -> i64 {
+    let mut res = 1i64;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            res = res.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exp >>= 1;
+    }
+    res
+}
+
+/// Integer division rounding towards negative infinity, so that
+/// `-7 ~/ 2 == -4` and `7 ~/ -2 == -4`.
+fn floor_div(v1: i64, v2: i64) -> i64 {
+    let quot = v1.wrapping_div(v2);
+    if v1.wrapping_rem(v2) != 0 && (v1 < 0) != (v2 < 0) {
+        quot - 1
+    } else {
+        quot
+    }
+}
+
+/// What a frame carries on with once the `finally` code it runs is done.
+enum Pending {
+    // leaving a loop or block, see `exit_block`
+    Exit(usize, Option<Value>),
+    Return(Value),
+    Raise(VMError)
+}
+
+enum BlockKind {
+    Loop,
+    // blocks have a scope of their own
+    Block,
+    // position of the `finally` code, if any
+    Try(Option<usize>),
+    // `finally` code running, with what to carry on with after it
+    Finally(Option<Pending>)
+}
+
+/// A `:` loop, inline block or `try` running in a frame, see `LOOP`,
+/// `BLOCK` and `TRY`.
+struct ActiveBlock {
+    // stack length below the value of the loop or block
+    stack_len: usize,
+    continue_pc: usize,
+    break_pc: usize,
+    kind: BlockKind
+}
+
+/// The innermost variable scope of a frame. Inline blocks get a scope of
+/// their own, which is only allocated once it is written to or captured.
+struct Scope {
+    vars: Gc<Variables>,
+    // scopes of inline blocks on top of `vars` that are still empty
+    pending: usize
+}
+
+impl Scope {
+    fn enter(&mut self) {
+        self.pending += 1;
+    }
+
+    fn leave(&mut self) -> Result<(), VMError> {
+        match self.pending {
+            0 => self.vars = self.vars.parent()?.clone(),
+            _ => self.pending -= 1
+        }
+        Ok(())
+    }
+
+    /// The variables `level` scopes out, `None` if that scope is still empty.
+    fn get(&self, level: usize) -> Result<Option<&Gc<Variables>>, VMError> {
+        if level < self.pending {
+            return Ok(None);
+        }
+        let mut vars = &self.vars;
+        for _ in self.pending .. level {
+            vars = vars.parent()?;
+        }
+        Ok(Some(vars))
+    }
+
+    /// Like `get`, allocating the scope at `level` if needed.
+    fn get_mut(&mut self, level: usize) -> Result<&Gc<Variables>, VMError> {
+        while self.pending > level {
+            self.vars = Variables::new_gc(Some(&self.vars));
+            self.pending -= 1;
+        }
+        self.get(level)?.ok_or(VMError::IllegalState)
+    }
+}
+
+struct Frame {
+    stack: Vec<Value>,
+    pc: usize,
+    // start of the instruction being run, to look up error handlers
+    op_pc: usize,
+    blocks: Vec<ActiveBlock>,
+    scope: Scope
+}
+
+/// Drops the loops and blocks from `depth` on, leaving their scopes.
+fn leave_blocks(blocks: &mut Vec<ActiveBlock>, scope: &mut Scope, depth: usize) -> Result<(), VMError> {
+    for block in blocks.drain(depth ..) {
+        if let BlockKind::Block = block.kind {
+            scope.leave()?;
+        }
+    }
+    Ok(())
+}
+
+/// The innermost `try` from `depth` on with `finally` code to run when left.
+fn find_finally(blocks: &[ActiveBlock], depth: usize) -> Option<usize> {
+    blocks.iter().rposition(|b| matches!(b.kind, BlockKind::Try(Some(_))))
+        .filter(|&idx| idx >= depth)
+}
+
+/// Leaves the `try` at `depth` for its `finally` code, which carries on
+/// with `pending` when done.
+fn run_finally(
+    stack: &mut Vec<Value>,
+    pc: &mut usize,
+    blocks: &mut Vec<ActiveBlock>,
+    scope: &mut Scope,
+    depth: usize,
+    pending: Pending
+) -> Result<(), VMError> {
+    let active = blocks.get(depth).ok_or(VMError::IllegalState)?;
+    let finally_pc = match active.kind {
+        BlockKind::Try(Some(finally_pc)) => finally_pc,
+        _ => return Err(VMError::IllegalState)
+    };
+    stack.truncate(active.stack_len);
+    leave_blocks(blocks, scope, depth)?;
+    blocks.push(ActiveBlock {
+        stack_len: stack.len(),
+        continue_pc: finally_pc,
+        break_pc: finally_pc,
+        kind: BlockKind::Finally(Some(pending))
+    });
+    *pc = finally_pc;
+    Ok(())
+}
+
+/// Leaves the loop or block at `depth` of a frame with `Some(value)`, or
+/// continues the loop at `depth` with `None`. The `finally` code of any
+/// `try` left on the way runs first.
+fn exit_block(
+    stack: &mut Vec<Value>,
+    pc: &mut usize,
+    blocks: &mut Vec<ActiveBlock>,
+    scope: &mut Scope,
+    depth: usize,
+    value: Option<Value>
+) -> Result<(), VMError> {
+    let kept = match value {
+        Some(_) => depth,
+        None => depth + 1
+    };
+    if let Some(idx) = find_finally(blocks, kept) {
+        return run_finally(stack, pc, blocks, scope, idx, Pending::Exit(depth, value));
+    }
+    let active = blocks.get(depth).ok_or(VMError::IllegalState)?;
+    stack.truncate(active.stack_len);
+    match value {
+        Some(value) => {
+            stack.push(value);
+            *pc = active.break_pc;
+        }
+        None => {
+            stack.push(Value::Null);
+            *pc = active.continue_pc;
+        }
+    }
+    leave_blocks(blocks, scope, kept)
+}
+
+/// Returns `value` from a frame, unless there is `finally` code to run first.
+fn exit_frame(
+    stack: &mut Vec<Value>,
+    pc: &mut usize,
+    blocks: &mut Vec<ActiveBlock>,
+    scope: &mut Scope,
+    value: Value
+) -> Result<Option<Value>, VMError> {
+    match find_finally(blocks, 0) {
+        Some(idx) => {
+            run_finally(stack, pc, blocks, scope, idx, Pending::Return(value))?;
+            Ok(None)
+        }
+        None => Ok(Some(value))
+    }
+}
+
+/// The metamethod `name` (e.g. `__add`) of `value`, own or inherited, if it
+/// is an object with one.
+fn metamethod(value: &Value, name: &str) -> Option<Value> {
+    match value {
+        Value::Object(obj) => obj.lookup(&name.encode_utf16().collect::<Vec<_>>()),
+        _ => None
+    }
+}
+
+/// Calls `func` with `receiver` as its `this`. Objects are called through
+/// their `__call` metamethod.
+fn call_value(ctx: &mut Context, state: &ProgramState, func: &Value, receiver: Value, args: Vec<Value>) -> Result<Value, VMError> {
+    match func {
+        Value::Closure(closure) => call_method(ctx, closure, receiver, args),
+        // native functions take no receiver, e.g. those of a library
+        Value::NativeFunction(native) => native(ctx, state, args),
+        _ => match metamethod(func, "__call") {
+            Some(method) => call_value(ctx, state, &method, func.clone(), args),
+            None => Err(VMError::invalid_type("closure/native function", func))
+        }
+    }
+}
+
+/// Applies the binary metamethod `name` of `v1`, or else of `v2`, which gets
+/// both operands as arguments. `None` if neither has one.
+fn binary_meta(ctx: &mut Context, state: &ProgramState, name: &str, v1: &Value, v2: &Value) -> Result<Option<Value>, VMError> {
+    for receiver in [v1, v2] {
+        if let Some(method) = metamethod(receiver, name) {
+            let args = vec![v1.clone(), v2.clone()];
+            return call_value(ctx, state, &method, receiver.clone(), args).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// `v1 < v2` by the `__lt` metamethod of either, if there is one.
+fn meta_lt(ctx: &mut Context, state: &ProgramState, v1: &Value, v2: &Value) -> Result<Option<bool>, VMError> {
+    binary_meta(ctx, state, "__lt", v1, v2)?.map(|res| res.as_bool()).transpose()
+}
+
+/// A field `obj` neither has nor inherits, which its `__index` metamethod
+/// may provide. Null otherwise.
+fn missing_field(ctx: &mut Context, state: &ProgramState, obj: &Value, key: Value) -> Result<Value, VMError> {
+    match metamethod(obj, "__index") {
+        Some(method) => call_value(ctx, state, &method, obj.clone(), vec![key]),
+        None => Ok(Value::Null)
+    }
+}
+
+/// Converts `value` for display, with any object in it converted by its
+/// `__str` metamethod if it has one.
+pub fn display(ctx: &mut Context, state: &ProgramState, value: &Value) -> Result<VMString, VMError> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        _ => format_value(ctx, state, value, false)
+    }
+}
+
+/// Converts `value` like a literal, with strings quoted. Objects are
+/// converted by their `__repr` metamethod, or else by `__str`.
+pub fn repr(ctx: &mut Context, state: &ProgramState, value: &Value) -> Result<VMString, VMError> {
+    format_value(ctx, state, value, true)
+}
+
+fn format_value(ctx: &mut Context, state: &ProgramState, value: &Value, repr: bool) -> Result<VMString, VMError> {
+    let str = value.format(repr, &mut |value, repr| {
+        let method = match repr {
+            true => metamethod(value, "__repr").or_else(|| metamethod(value, "__str")),
+            false => metamethod(value, "__str")
+        };
+        match method {
+            Some(method) => Ok(Some(call_value(ctx, state, &method, value.clone(), vec![])?.as_str()?.to_string())),
+            None => Ok(None)
+        }
+    })?;
+    Ok(VMString::from(&str[..]))
+}
+
+/// Jumps to the handler of the exception table covering the instruction
+/// that raised `err`. Returns `err` back if there is none.
+fn handle_error(ctx: &Context, state: &ProgramState, frame: &mut Frame, err: VMError) -> Result<(), VMError> {
+    let Frame { stack, pc, op_pc, blocks, scope } = frame;
+    let handlers = &ctx.get_program(state.program_idx).func_list[state.func_idx].handlers;
+    let handler = handlers.iter()
+        .find(|h| h.covers(*op_pc) && (h.cleanup || err.is_catchable()));
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return Err(err)
+    };
+
+    let depth: usize = handler.depth.into();
+    let active = blocks.get(depth).ok_or(VMError::IllegalState)?;
+    if !matches!(active.kind, BlockKind::Try(_)) {
+        return Err(VMError::IllegalState);
+    }
+    if handler.cleanup {
+        run_finally(stack, pc, blocks, scope, depth, Pending::Raise(err))?;
+    } else {
+        // the `catch` code is still inside the `try`, for its `finally`
+        stack.truncate(active.stack_len);
+        leave_blocks(blocks, scope, depth + 1)?;
+        stack.push(err.into_value());
+    }
+    *pc = handler.target;
+    Ok(())
+}
+
 fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMError> {
     if state.func_idx >= ctx.get_program(state.program_idx).func_list.len() {
         return Err(VMError::FunctionIndexOutOfBound);
     }
 
-    let this = state.variables.this_obj();
+    let mut frame = Frame {
+        stack: vec![],
+        pc: 0,
+        op_pc: 0,
+        blocks: vec![],
+        scope: Scope { vars: state.variables.clone(), pending: 0 }
+    };
 
-    let mut stack = vec![];
+    loop {
+        match run_frame(ctx, &state, &mut frame) {
+            Ok(value) => return Ok(value),
+            // a `break` or `continue` from a closure called inside one of our loops
+            Err(VMError::Unwind(unwind))
+                if Gc::ptr_eq(&unwind.target, &state.variables) && frame.blocks.get(unwind.depth)
+                    .is_some_and(|b| matches!(b.kind, BlockKind::Loop) && b.continue_pc == unwind.start) => {
+                let Unwind { depth, value, .. } = *unwind;
+                let Frame { stack, pc, blocks, scope, .. } = &mut frame;
+                exit_block(stack, pc, blocks, scope, depth, value)?;
+            }
+            // from a closure kept after its loop ended, which we may catch
+            Err(VMError::Unwind(unwind)) if Gc::ptr_eq(&unwind.target, &state.variables) => {
+                let err = VMError::LoopNotRunning { is_break: unwind.value.is_some() };
+                handle_error(ctx, &state, &mut frame, err)?
+            }
+            Err(err) => handle_error(ctx, &state, &mut frame, err)?
+        }
+    }
+}
 
-    let mut pc = 0usize;
+fn run_frame(ctx: &mut Context, state: &ProgramState, frame: &mut Frame) -> Result<Value, VMError> {
+    let Frame { stack, pc, op_pc, blocks, scope } = frame;
 
     loop {
         let program = ctx.get_program(state.program_idx);
-        let cur_func = &program.func_list[state.func_idx];
+        let cur_func = &program.func_list[state.func_idx].code;
 
-        let code = next(&cur_func, &mut pc)?;
+        *op_pc = *pc;
+        let code = next(cur_func, pc)?;
 
         match code {
-            code::LOAD => {
-                let str = next_str(&cur_func, &mut pc, program)?;
-                match this.get().get(str) {
-                    Some(v) => stack.push(v.clone()),
-                    None => stack.push(Value::Null)
-                }
-            }
-            code::LOAD_SUPER => {
-                let str = next_str(&cur_func, &mut pc, program)?;
-                match state.variables.parent_obj()?.get().get(str) {
-                    Some(v) => stack.push(v.clone()),
+            code::LOAD | code::LOAD_SUPER => {
+                let str = next_str(cur_func, pc, program)?;
+                let level = match code {
+                    code::LOAD => 0,
+                    _ => 1
+                };
+                match scope.get(level)?.and_then(|vars| vars.this_obj().get().get(str).cloned()) {
+                    Some(v) => stack.push(v),
                     None => stack.push(Value::Null)
                 }
             }
             code::LOAD_FIELD => {
-                let str = next_str(&cur_func, &mut pc, program)?;
-                let obj = stack_pop(&mut stack)?;
-                match obj.as_obj()?.get().get(str) {
-                    Some(v) => stack.push(v.clone()),
-                    None => stack.push(Value::Null)
+                let str = next_str(cur_func, pc, program)?;
+                let obj = stack_pop(stack)?;
+                let value = match obj.as_obj()?.lookup(str) {
+                    Some(value) => value,
+                    None => {
+                        let key = Value::String(str.into());
+                        missing_field(ctx, state, &obj, key)?
+                    }
                 };
+                stack.push(value);
             }
             code::LOAD_ITEM => {
-                let idx = stack_pop(&mut stack)?;
-                let obj = stack_pop(&mut stack)?;
+                let idx = stack_pop(stack)?;
+                let obj = stack_pop(stack)?;
                 match &obj {
                     Value::String(s) => {
                         let idx = idx.as_idx()?;
@@ -94,11 +452,18 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                         stack.push(Value::String([char][..].into()));
                     }
                     Value::Object(o) => {
-                        let idx = idx.as_str()?;
-                        match o.get().get(idx) {
-                            Some(v) => stack.push(v.clone()),
-                            None => stack.push(Value::Null)
-                        }
+                        let value = match &idx {
+                            Value::String(s) => o.lookup(s.data()),
+                            // keys other than strings are only for `__index`
+                            _ if metamethod(&obj, "__index").is_none() =>
+                                return Err(VMError::invalid_type("string", &idx)),
+                            _ => None
+                        };
+                        let value = match value {
+                            Some(value) => value,
+                            None => missing_field(ctx, state, &obj, idx)?
+                        };
+                        stack.push(value);
                     }
                     Value::Array(a) => {
                         let idx = idx.as_idx()?;
@@ -107,13 +472,17 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                             .clone();
                         stack.push(elem);
                     }
-                    _ => return Err(VMError::invalid_type("object/array", &obj))
+                    Value::Map(m) => {
+                        let value = m.get().get(&MapKey::new(idx)?).cloned();
+                        stack.push(value.unwrap_or(Value::Null));
+                    }
+                    _ => return Err(VMError::invalid_type("object/array/map", &obj))
                 }
             }
             code::LOAD_SLICE => {
-                let end = stack_pop(&mut stack)?.as_slice_idx()?;
-                let start = stack_pop(&mut stack)?.as_slice_idx()?.unwrap_or(0);
-                let obj = stack_pop(&mut stack)?;
+                let end = stack_pop(stack)?.as_slice_idx()?;
+                let start = stack_pop(stack)?.as_slice_idx()?.unwrap_or(0);
+                let obj = stack_pop(stack)?;
                 let slice = match &obj {
                     Value::String(s) => {
                         let str = s.data();
@@ -133,35 +502,36 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                 };
                 stack.push(slice);
             }
-            code::STORE => {
-                let str = next_str(&cur_func, &mut pc, program)?;
-                let value = stack_pop(&mut stack)?;
-                match &value {
-                    Value::Null => this.get_mut()?.remove(str),
-                    _ => this.get_mut()?.insert(str.into(), value)
+            code::STORE | code::STORE_SUPER => {
+                let str = next_str(cur_func, pc, program)?;
+                let level = match code {
+                    code::STORE => 0,
+                    _ => 1
                 };
-            }
-            code::STORE_SUPER => {
-                let str = next_str(&cur_func, &mut pc, program)?;
-                let value = stack_pop(&mut stack)?;
+                let value = stack_pop(stack)?;
                 match &value {
-                    Value::Null => state.variables.parent_obj()?.get_mut()?.remove(str),
-                    _ => state.variables.parent_obj()?.get_mut()?.insert(str.into(), value)
+                    // nothing to remove from a scope that does not exist yet
+                    Value::Null => if let Some(vars) = scope.get(level)? {
+                        vars.this_obj().get_mut()?.remove(str);
+                    }
+                    _ => {
+                        scope.get_mut(level)?.this_obj().get_mut()?.insert(str.into(), value);
+                    }
                 };
             }
             code::STORE_FIELD => {
-                let str = next_str(&cur_func, &mut pc, program)?;
-                let value = stack_pop(&mut stack)?;
-                let obj = stack_pop(&mut stack)?;
+                let str = next_str(cur_func, pc, program)?;
+                let value = stack_pop(stack)?;
+                let obj = stack_pop(stack)?;
                 match &value {
                     Value::Null => obj.as_obj()?.get_mut()?.remove(str),
                     _ => obj.as_obj()?.get_mut()?.insert(str.into(), value.clone())
                 };
             }
             code::STORE_ITEM => {
-                let value = stack_pop(&mut stack)?;
-                let idx = stack_pop(&mut stack)?;
-                let obj = stack_pop(&mut stack)?;
+                let value = stack_pop(stack)?;
+                let idx = stack_pop(stack)?;
+                let obj = stack_pop(stack)?;
                 match &obj {
                     Value::Object(o) => {
                         let idx = idx.as_str()?;
@@ -175,14 +545,21 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                         *a.get_mut()?.get_mut(idx)
                             .ok_or(VMError::ArrayIndexOutOfBound)? = value.clone();
                     }
-                    _ => return Err(VMError::invalid_type("object/array", &obj))
+                    Value::Map(m) => {
+                        let key = MapKey::new(idx)?;
+                        match &value {
+                            Value::Null => m.get_mut()?.remove(&key),
+                            _ => m.get_mut()?.insert(key, value.clone())
+                        };
+                    }
+                    _ => return Err(VMError::invalid_type("object/array/map", &obj))
                 }
             }
             code::STORE_SLICE => {
-                let value = stack_pop(&mut stack)?;
-                let end = stack_pop(&mut stack)?.as_slice_idx()?;
-                let start = stack_pop(&mut stack)?.as_slice_idx()?.unwrap_or(0);
-                let obj = stack_pop(&mut stack)?;
+                let value = stack_pop(stack)?;
+                let end = stack_pop(stack)?.as_slice_idx()?;
+                let start = stack_pop(stack)?.as_slice_idx()?.unwrap_or(0);
+                let obj = stack_pop(stack)?;
                 match &obj {
                     Value::Array(a) => {
                         let mut arr = a.get_mut()?;
@@ -194,7 +571,7 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                     _ => return Err(VMError::invalid_type("array", &obj))
                 };
             }
-            code::DUP => stack.push(stack_top(&stack)?.clone()),
+            code::DUP => stack.push(stack_top(stack)?.clone()),
             code::DUP_PRE2 => {
                 if stack.len() < 2 {
                     return Err(VMError::BadStack);
@@ -214,15 +591,15 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                 stack.insert(stack.len() - 4, stack.last().unwrap().clone());
             }
             code::POP => {
-                stack_pop(&mut stack)?;
+                stack_pop(stack)?;
             }
             code::PUSH_NULL => stack.push(Value::Null),
             code::PUSH_INT => {
-                let i = next(&cur_func, &mut pc)? as i8;
+                let i = next(cur_func, pc)? as i8;
                 stack.push(Value::Int(i.into()));
             }
             code::PUSH_CONST => {
-                let const_idx: usize = next(&cur_func, &mut pc)?.into();
+                let const_idx: usize = next(cur_func, pc)?.into();
                 let value = match get_constant(program, const_idx)? {
                     Constant::Int(v) => Value::Int(*v),
                     Constant::Float(v) => Value::Float(*v),
@@ -231,205 +608,415 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                 stack.push(value);
             }
             code::NEW_ARRAY => {
-                let cnt: usize = next(&cur_func, &mut pc)?.into();
+                let cnt: usize = next(cur_func, pc)?.into();
                 if stack.len() < cnt {
                     return Err(VMError::BadStack);
                 }
                 let arr = stack.drain(stack.len() - cnt ..).collect();
                 stack.push(Value::new_arr(arr));
             }
+            code::CONCAT => {
+                let cnt: usize = next(cur_func, pc)?.into();
+                if stack.len() < cnt {
+                    return Err(VMError::BadStack);
+                }
+                let values: Vec<_> = stack.drain(stack.len() - cnt ..).collect();
+                let mut parts = Vec::with_capacity(cnt);
+                for value in &values {
+                    parts.push(display(ctx, state, value)?);
+                }
+                let mut str = Vec::with_capacity(parts.iter().map(|s| s.data().len()).sum());
+                for part in &parts {
+                    str.extend_from_slice(part.data());
+                }
+                stack.push(Value::String(str.into()));
+            }
             code::PUSH_ARG => {
-                let arg_idx: usize = next(&cur_func, &mut pc)?.into();
+                let arg_idx: usize = next(cur_func, pc)?.into();
                 stack.push(state.args.get(arg_idx).unwrap_or(&Value::Null).clone());
             }
-            code::PUSH_SELF => stack.push(state.variables.this().clone()),
+            code::PUSH_ARGS => {
+                let arg_idx: usize = next(cur_func, pc)?.into();
+                let rest = state.args.get(arg_idx ..).unwrap_or_default();
+                stack.push(Value::new_arr(rest.to_vec()));
+            }
+            code::CHECK_ARGS => {
+                let max: usize = next(cur_func, pc)?.into();
+                if state.args.len() > max {
+                    return Err(VMError::TooManyArguments { expected: max, got: state.args.len() });
+                }
+            }
+            code::PUSH_SELF => stack.push(scope.get_mut(0)?.this().clone()),
+            code::PUSH_THIS => stack.push(state.receiver.clone()),
             code::PUSH_SUPER => {
-                let lvl: u64 = next(&cur_func, &mut pc)?.into();
-                stack.push(state.variables.ancestor(lvl)?.clone());
+                let lvl: usize = next(cur_func, pc)?.into();
+                stack.push(scope.get_mut(lvl + 1)?.this().clone());
             }
             code::PUSH_CLOSURE => {
-                let idx: usize = next(&cur_func, &mut pc)?.into();
+                let idx: usize = next(cur_func, pc)?.into();
                 let closure = Closure {
-                    parent: state.variables.clone(),
+                    parent: scope.get_mut(0)?.clone(),
                     program_idx: state.program_idx,
                     func_idx: idx
                 };
                 stack.push(Value::Closure(closure));
             }
             code::JMP => {
-                let offset = next(&cur_func, &mut pc)?;
-                jump(&mut pc, offset)?;
+                let offset = next_offset(cur_func, pc)?;
+                jump(pc, offset)?;
             }
             code::JN => {
-                let offset = next(&cur_func, &mut pc)?;
-                if let Value::Null = stack_pop(&mut stack)? {
-                    jump(&mut pc, offset)?;
+                let offset = next_offset(cur_func, pc)?;
+                if let Value::Null = stack_pop(stack)? {
+                    jump(pc, offset)?;
                 }
             }
             code::JT => {
-                let offset = next(&cur_func, &mut pc)?;
-                if stack_pop(&mut stack)?.as_bool()? {
-                    jump(&mut pc, offset)?;
+                let offset = next_offset(cur_func, pc)?;
+                if stack_pop(stack)?.as_bool()? {
+                    jump(pc, offset)?;
                 }
             }
             code::JF => {
-                let offset = next(&cur_func, &mut pc)?;
-                if !stack_pop(&mut stack)?.as_bool()? {
-                    jump(&mut pc, offset)?;
+                let offset = next_offset(cur_func, pc)?;
+                if !stack_pop(stack)?.as_bool()? {
+                    jump(pc, offset)?;
+                }
+            }
+            code::LOOP | code::BLOCK => {
+                let offset = next_offset(cur_func, pc)?;
+                let mut break_pc = *pc;
+                jump(&mut break_pc, offset)?;
+                let (stack_len, kind) = match code {
+                    code::BLOCK => {
+                        scope.enter();
+                        (stack.len(), BlockKind::Block)
+                    }
+                    // a loop's value is already on the stack
+                    _ => (stack.len().checked_sub(1).ok_or(VMError::BadStack)?, BlockKind::Loop)
+                };
+                blocks.push(ActiveBlock { stack_len, continue_pc: *pc, break_pc, kind });
+            }
+            code::END_LOOP | code::END_BLOCK => {
+                let block = blocks.pop().ok_or(VMError::IllegalState)?;
+                if let BlockKind::Block = block.kind {
+                    scope.leave()?;
+                }
+            }
+            code::TRY => {
+                let offset = next_offset(cur_func, pc)?;
+                // an offset of 0 means there is no `finally` code
+                let finally_pc = match offset {
+                    0 => None,
+                    offset => {
+                        let mut finally_pc = *pc;
+                        jump(&mut finally_pc, offset)?;
+                        Some(finally_pc)
+                    }
+                };
+                blocks.push(ActiveBlock {
+                    stack_len: stack.len(),
+                    continue_pc: *pc,
+                    break_pc: *pc,
+                    kind: BlockKind::Try(finally_pc)
+                });
+            }
+            code::END_TRY => {
+                let block = blocks.pop().ok_or(VMError::IllegalState)?;
+                match block.kind {
+                    // the `finally` code follows, with nothing to carry on with
+                    BlockKind::Try(Some(_)) => blocks.push(ActiveBlock {
+                        kind: BlockKind::Finally(None),
+                        ..block
+                    }),
+                    BlockKind::Try(None) => {}
+                    _ => return Err(VMError::IllegalState)
                 }
             }
+            code::THROW => return Err(VMError::Thrown(stack_pop(stack)?)),
+            code::END_FINALLY => {
+                let block = blocks.pop().ok_or(VMError::IllegalState)?;
+                match block.kind {
+                    BlockKind::Finally(None) => {}
+                    BlockKind::Finally(Some(Pending::Exit(depth, value))) =>
+                        exit_block(stack, pc, blocks, scope, depth, value)?,
+                    BlockKind::Finally(Some(Pending::Return(value))) => {
+                        if let Some(value) = exit_frame(stack, pc, blocks, scope, value)? {
+                            return Ok(value);
+                        }
+                    }
+                    BlockKind::Finally(Some(Pending::Raise(err))) => return Err(err),
+                    _ => return Err(VMError::IllegalState)
+                }
+            }
+            code::BREAK | code::CONTINUE => {
+                let level: usize = next(cur_func, pc)?.into();
+                let depth: usize = next(cur_func, pc)?.into();
+                let start: usize = u16::from_le_bytes([next(cur_func, pc)?, next(cur_func, pc)?]).into();
+                let value = match code {
+                    code::BREAK => Some(stack_pop(stack)?),
+                    _ => None
+                };
+                if level > 0 {
+                    // the loop belongs to an enclosing function, unwind to its frame
+                    return Err(VMError::Unwind(Box::new(Unwind {
+                        target: scope.get(level)?.ok_or(VMError::IllegalState)?.clone(),
+                        depth,
+                        start,
+                        value
+                    })));
+                }
+                exit_block(stack, pc, blocks, scope, depth, value)?;
+            }
             code::CALL => {
-                let arg_cnt: usize = next(&cur_func, &mut pc)?.into();
+                let arg_cnt: usize = next(cur_func, pc)?.into();
                 if stack.len() < 1 + arg_cnt {
                     return Err(VMError::BadStack);
                 }
                 let args = stack.drain(stack.len() - arg_cnt ..).collect();
                 let func = stack.pop().unwrap();
-                match &func {
-                    Value::Closure(closure) =>
-                        stack.push(call(ctx, closure, args)?),
-                    Value::NativeFunction(func) =>
-                        stack.push(func(ctx, &state, args)?),
-                    v =>
-                        return Err(VMError::invalid_type("closure/native function", v))
+                stack.push(call_value(ctx, state, &func, Value::Null, args)?);
+            }
+            code::CALL_METHOD => {
+                let str = next_str(cur_func, pc, program)?;
+                let arg_cnt: usize = next(cur_func, pc)?.into();
+                if stack.len() < 1 + arg_cnt {
+                    return Err(VMError::BadStack);
+                }
+                let args = stack.drain(stack.len() - arg_cnt ..).collect();
+                let receiver = stack.pop().unwrap();
+                let func = match receiver.as_obj()?.lookup(str) {
+                    Some(func) => func,
+                    None => {
+                        let key = Value::String(str.into());
+                        missing_field(ctx, state, &receiver, key)?
+                    }
+                };
+                stack.push(call_value(ctx, state, &func, receiver, args)?);
+            }
+            code::RETURN => {
+                let value = stack_pop(stack)?;
+                if let Some(value) = exit_frame(stack, pc, blocks, scope, value)? {
+                    return Ok(value);
                 }
             }
-            code::RETURN => break,
             code::ADD => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
-                match v1 {
-                    Value::Int(v1) => *v1 = v1.wrapping_add(v2.as_int()?),
-                    Value::Float(v1) => *v1 += v2.as_float()?,
-                    Value::String(s) => {
-                        let str = [s.data(), v2.as_str()?.data()].concat();
-                        *v1 = Value::String(str[..].into());
-                    }
-                    Value::Array(a) => {
-                        let arr = [&a.get()[..], &v2.as_arr()?.get()[..]].concat();
-                        *v1 = Value::new_arr(arr);
+                let v2 = stack_pop(stack)?;
+                if let Some(res) = binary_meta(ctx, state, "__add", stack_top(stack)?, &v2)? {
+                    *stack_top_mut(stack)? = res;
+                    continue;
+                }
+                let v1 = stack_top_mut(stack)?;
+                let is_numeric = numeric_op(v1, &v2,
+                    |a, b| Ok(Value::Int(a.wrapping_add(b))),
+                    |a, b| a + b)?;
+                if !is_numeric {
+                    match v1 {
+                        Value::String(s) => {
+                            let str = [s.data(), v2.as_str()?.data()].concat();
+                            *v1 = Value::String(str[..].into());
+                        }
+                        Value::Array(a) => {
+                            let arr = [&a.get()[..], &v2.as_arr()?.get()[..]].concat();
+                            *v1 = Value::new_arr(arr);
+                        }
+                        _ => return Err(VMError::invalid_type("int/float/string/array", v1))
                     }
-                    _ => return Err(VMError::invalid_type("int/float/string/array", v1))
                 }
             }
             code::SUB => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
-                match v1 {
-                    Value::Int(v1) => *v1 = v1.wrapping_sub(v2.as_int()?),
-                    Value::Float(v1) => *v1 -= v2.as_float()?,
-                    _ => return Err(VMError::invalid_type("int/float", v1))
+                let v2 = stack_pop(stack)?;
+                if let Some(res) = binary_meta(ctx, state, "__sub", stack_top(stack)?, &v2)? {
+                    *stack_top_mut(stack)? = res;
+                    continue;
+                }
+                let v1 = stack_top_mut(stack)?;
+                if !numeric_op(v1, &v2, |a, b| Ok(Value::Int(a.wrapping_sub(b))), |a, b| a - b)? {
+                    return Err(VMError::invalid_type("int/float", v1));
                 }
             }
             code::MUL => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
-                match v1 {
-                    Value::Int(v1) => *v1 = v1.wrapping_mul(v2.as_int()?),
-                    Value::Float(v1) => *v1 *= v2.as_float()?,
-                    _ => return Err(VMError::invalid_type("int/float", v1))
+                let v2 = stack_pop(stack)?;
+                if let Some(res) = binary_meta(ctx, state, "__mul", stack_top(stack)?, &v2)? {
+                    *stack_top_mut(stack)? = res;
+                    continue;
+                }
+                let v1 = stack_top_mut(stack)?;
+                if !numeric_op(v1, &v2, |a, b| Ok(Value::Int(a.wrapping_mul(b))), |a, b| a * b)? {
+                    return Err(VMError::invalid_type("int/float", v1));
                 }
             }
             code::DIV => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
-                match v1 {
-                    Value::Int(v1) => {
-                        let v2 = v2.as_int()?;
-                        if v2 == 0 {
-                            return Err(VMError::DivideByZeroError);
-                        }
-                        *v1 = v1.wrapping_div(v2)
-                    }
-                    Value::Float(v1) => *v1 /= v2.as_float()?,
-                    _ => return Err(VMError::invalid_type("int/float", v1))
+                let v2 = stack_pop(stack)?;
+                if let Some(res) = binary_meta(ctx, state, "__div", stack_top(stack)?, &v2)? {
+                    *stack_top_mut(stack)? = res;
+                    continue;
+                }
+                let v1 = stack_top_mut(stack)?;
+                let is_numeric = numeric_op(v1, &v2,
+                    |a, b| match b {
+                        0 => Err(VMError::DivideByZeroError),
+                        b => Ok(Value::Int(a.wrapping_div(b)))
+                    },
+                    |a, b| a / b)?;
+                if !is_numeric {
+                    return Err(VMError::invalid_type("int/float", v1));
                 }
             }
             code::MOD => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
-                let v1 = v1.as_int_mut()?;
-                let v2 = v2.as_int()?;
-                if v2 == 0 {
-                    return Err(VMError::DivideByZeroError);
+                let v2 = stack_pop(stack)?;
+                if let Some(res) = binary_meta(ctx, state, "__mod", stack_top(stack)?, &v2)? {
+                    *stack_top_mut(stack)? = res;
+                    continue;
+                }
+                let v1 = stack_top_mut(stack)?;
+                let is_numeric = numeric_op(v1, &v2,
+                    |a, b| match b {
+                        0 => Err(VMError::DivideByZeroError),
+                        b => Ok(Value::Int(a.wrapping_rem(b)))
+                    },
+                    |a, b| a % b)?;
+                if !is_numeric {
+                    return Err(VMError::invalid_type("int/float", v1));
                 }
-                *v1 = v1.wrapping_rem(v2);
             }
             code::NEG => {
-                let v = stack_top_mut(&mut stack)?;
+                if let Some(method) = metamethod(stack_top(stack)?, "__neg") {
+                    let v = stack_pop(stack)?;
+                    stack.push(call_value(ctx, state, &method, v, vec![])?);
+                    continue;
+                }
+                let v = stack_top_mut(stack)?;
                 match v {
                     Value::Int(v) => *v = v.wrapping_neg(),
                     Value::Float(v) => *v = -*v,
                     _ => return Err(VMError::invalid_type("int/float", v))
                 }
             }
-            code::CMP_EQ => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_pop(&mut stack)?;
-                stack.push(Value::Bool(v1.cmp_eq(&v2)));
+            code::POW => {
+                let v2 = stack_pop(stack)?;
+                if let Some(res) = binary_meta(ctx, state, "__pow", stack_top(stack)?, &v2)? {
+                    *stack_top_mut(stack)? = res;
+                    continue;
+                }
+                let v1 = stack_top_mut(stack)?;
+                let is_numeric = numeric_op(v1, &v2,
+                    // a negative exponent gives a fraction, so the result is a float
+                    |a, b| Ok(match u32::try_from(b) {
+                        Ok(b) => Value::Int(a.wrapping_pow(b)),
+                        Err(_) if b > 0 => Value::Int(int_pow(a, b)),
+                        Err(_) => Value::Float((a as f64).powf(b as f64))
+                    }),
+                    f64::powf)?;
+                if !is_numeric {
+                    return Err(VMError::invalid_type("int/float", v1));
+                }
             }
-            code::CMP_NE => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_pop(&mut stack)?;
-                stack.push(Value::Bool(!v1.cmp_eq(&v2)));
+            code::IDIV => {
+                let v2 = stack_pop(stack)?;
+                if let Some(res) = binary_meta(ctx, state, "__idiv", stack_top(stack)?, &v2)? {
+                    *stack_top_mut(stack)? = res;
+                    continue;
+                }
+                let v1 = stack_top_mut(stack)?;
+                let is_numeric = numeric_op(v1, &v2,
+                    |a, b| match b {
+                        0 => Err(VMError::DivideByZeroError),
+                        b => Ok(Value::Int(floor_div(a, b)))
+                    },
+                    |a, b| (a / b).floor())?;
+                if !is_numeric {
+                    return Err(VMError::invalid_type("int/float", v1));
+                }
             }
+            code::CMP_EQ | code::CMP_NE => {
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_pop(stack)?;
+                // only between two objects, so that e.g. `v == nil` needs no care
+                let meta = match (&v1, &v2) {
+                    (Value::Object(_), Value::Object(_)) => binary_meta(ctx, state, "__eq", &v1, &v2)?,
+                    _ => None
+                };
+                let eq = match meta {
+                    Some(res) => res.as_bool()?,
+                    None => v1.cmp_eq(&v2)
+                };
+                stack.push(Value::Bool(eq == (code == code::CMP_EQ)));
+            }
+            // `__lt` covers all four, with the operands swapped for `>` and `<=`
             code::CMP_GT => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_pop(&mut stack)?;
-                stack.push(Value::Bool(v1.cmp_gt(&v2)?));
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_pop(stack)?;
+                let gt = match meta_lt(ctx, state, &v2, &v1)? {
+                    Some(res) => res,
+                    None => v1.cmp_gt(&v2)?
+                };
+                stack.push(Value::Bool(gt));
             }
             code::CMP_LT => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_pop(&mut stack)?;
-                stack.push(Value::Bool(v1.cmp_lt(&v2)?));
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_pop(stack)?;
+                let lt = match meta_lt(ctx, state, &v1, &v2)? {
+                    Some(res) => res,
+                    None => v1.cmp_lt(&v2)?
+                };
+                stack.push(Value::Bool(lt));
             }
             code::CMP_GE => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_pop(&mut stack)?;
-                stack.push(Value::Bool(!v1.cmp_lt(&v2)?));
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_pop(stack)?;
+                let lt = match meta_lt(ctx, state, &v1, &v2)? {
+                    Some(res) => res,
+                    None => v1.cmp_lt(&v2)?
+                };
+                stack.push(Value::Bool(!lt));
             }
             code::CMP_LE => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_pop(&mut stack)?;
-                stack.push(Value::Bool(!v1.cmp_gt(&v2)?));
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_pop(stack)?;
+                let gt = match meta_lt(ctx, state, &v2, &v1)? {
+                    Some(res) => res,
+                    None => v1.cmp_gt(&v2)?
+                };
+                stack.push(Value::Bool(!gt));
             }
             code::NOT => {
-                let v = stack_top_mut(&mut stack)?;
+                let v = stack_top_mut(stack)?;
                 match v {
                     Value::Bool(b) => *b = !*b,
                     _ => return Err(VMError::invalid_type("bool", v))
                 }
             }
             code::BAND => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_top_mut(stack)?;
                 *v1.as_int_mut()? &= v2.as_int()?;
             }
             code::BOR => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_top_mut(stack)?;
                 *v1.as_int_mut()? |= v2.as_int()?;
             }
             code::BXOR => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_top_mut(stack)?;
                 *v1.as_int_mut()? ^= v2.as_int()?;
             }
             code::BINV => {
-                let v = stack_top_mut(&mut stack)?;
+                let v = stack_top_mut(stack)?;
                 let i = v.as_int_mut()?;
                 *i = !*i;
             }
             code::SHL => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_top_mut(stack)?;
                 let v1 = v1.as_int_mut()?;
                 let v2 = v2.as_int()?;
                 *v1 = v1.wrapping_shl(v2 as u32);
             }
             code::SHR => {
-                let v2 = stack_pop(&mut stack)?;
-                let v1 = stack_top_mut(&mut stack)?;
+                let v2 = stack_pop(stack)?;
+                let v1 = stack_top_mut(stack)?;
                 match v1 {
                     Value::Int(v1) => {
                         let v2 = v2.as_int()?;
@@ -467,39 +1054,43 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                 }
             }
             code::TYPE => {
-                let value = stack_pop(&mut stack)?;
+                let value = stack_pop(stack)?;
                 let type_func = match &value {
                     Value::Null => Value::NativeFunction(|_, _, args| {
-                        let [value] = Value::extract_args(args)?;
+                        let [value] = Value::extract_args("null", args)?;
                         match &value {
                             Value::Null => Ok(value),
-                            _ => Err(VMError::invalid_type("null", &value))
+                            _ => Err(VMError::invalid_type("null", &value).at_arg("null", 0))
                         }
                     }),
                     Value::Int(_) => Value::NativeFunction(|_, _, args| {
-                        let [value] = Value::extract_args(args)?;
+                        let [value] = Value::extract_args("int", args)?;
                         let res_int = match &value {
                             Value::Int(i) => *i,
                             Value::Float(f) => *f as i64,
                             Value::String(s) => i64::from_str(&s.to_string())
-                                .map_err(|_| VMError::IllegalFunctionArguments)?,
-                            _ => return Err(VMError::invalid_type("int/float/string", &value))
+                                .map_err(|e| VMError::custom("ValueError",
+                                    format!("cannot convert \"{}\" to int: {}", s.to_string(), e),
+                                    value.clone()))?,
+                            _ => return Err(VMError::invalid_type("int/float/string", &value).at_arg("int", 0))
                         };
                         Ok(Value::Int(res_int))
                     }),
                     Value::Float(_) => Value::NativeFunction(|_, _, args| {
-                        let [value] = Value::extract_args(args)?;
+                        let [value] = Value::extract_args("float", args)?;
                         let res_float = match &value {
                             Value::Int(i) => *i as f64,
                             Value::Float(f) => *f,
                             Value::String(s) => f64::from_str(&s.to_string())
-                                .map_err(|_| VMError::IllegalFunctionArguments)?,
-                            _ => return Err(VMError::invalid_type("int/float/string", &value))
+                                .map_err(|e| VMError::custom("ValueError",
+                                    format!("cannot convert \"{}\" to float: {}", s.to_string(), e),
+                                    value.clone()))?,
+                            _ => return Err(VMError::invalid_type("int/float/string", &value).at_arg("float", 0))
                         };
                         Ok(Value::Float(res_float))
                     }),
                     Value::Bool(_) => Value::NativeFunction(|_, _, args| {
-                        let [value] = Value::extract_args(args)?;
+                        let [value] = Value::extract_args("bool", args)?;
                         let res_bool = match &value {
                             Value::Null => false,
                             Value::Int(i) => *i != 0,
@@ -508,60 +1099,79 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                             Value::String(s) => s.data().len() > 0,
                             Value::Object(o) => o.get().len() > 0,
                             Value::Array(a) => a.get().len() > 0,
+                            Value::Map(m) => !m.get().is_empty(),
                             Value::Closure(_) => true,
                             Value::NativeFunction(_) => true
                         };
                         Ok(Value::Bool(res_bool))
                     }),
-                    Value::String(_) => Value::NativeFunction(|_, _, args| {
-                        let [value] = Value::extract_args(args)?;
-                        match &value {
-                            Value::String(_) => Ok(value),
-                            _ => Ok(Value::String(value.to_string()[..].into()))
-                        }
+                    Value::String(_) => Value::NativeFunction(|ctx, state, args| {
+                        let ([value], [as_repr]) = Value::extract_args_and_optional("string", args)?;
+                        let as_repr = match as_repr {
+                            Some(v) => v.as_bool().map_err(|e| e.at_arg("string", 1))?,
+                            None => false
+                        };
+                        let str = match as_repr {
+                            true => repr(ctx, state, &value)?,
+                            false => display(ctx, state, &value)?
+                        };
+                        Ok(Value::String(str))
                     }),
                     Value::Object(_) => Value::NativeFunction(|_, _, args| {
-                        let [value] = Value::extract_args(args)?;
+                        let [value] = Value::extract_args("object", args)?;
                         match &value {
                             Value::Object(_) => Ok(value),
-                            _ => Err(VMError::invalid_type("object", &value))
+                            _ => Err(VMError::invalid_type("object", &value).at_arg("object", 0))
                         }
                     }),
                     Value::Array(_) => Value::NativeFunction(|_, _, args| {
-                        let [value] = Value::extract_args(args)?;
+                        let [value] = Value::extract_args("array", args)?;
                         match &value {
                             Value::Array(_) => Ok(value),
-                            _ => Err(VMError::invalid_type("array", &value))
+                            _ => Err(VMError::invalid_type("array", &value).at_arg("array", 0))
+                        }
+                    }),
+                    Value::Map(_) => Value::NativeFunction(|_, _, args| {
+                        let [value] = Value::extract_args("map", args)?;
+                        match &value {
+                            Value::Map(_) => Ok(value),
+                            _ => Err(VMError::invalid_type("map", &value).at_arg("map", 0))
                         }
                     }),
                     Value::Closure(_) => Value::NativeFunction(|_, _, args| {
-                        let [value] = Value::extract_args(args)?;
+                        let [value] = Value::extract_args("closure", args)?;
                         match &value {
                             Value::Closure(_) => Ok(value),
-                            _ => Err(VMError::invalid_type("closure", &value))
+                            _ => Err(VMError::invalid_type("closure", &value).at_arg("closure", 0))
                         }
                     }),
                     Value::NativeFunction(_) => Value::NativeFunction(|_, _, args| {
-                        let [value] = Value::extract_args(args)?;
+                        let [value] = Value::extract_args("native function", args)?;
                         match &value {
                             Value::NativeFunction(_) => Ok(value),
-                            _ => Err(VMError::invalid_type("native function", &value))
+                            _ => Err(VMError::invalid_type("native function", &value).at_arg("native function", 0))
                         }
                     })
                 };
                 stack.push(type_func);
             }
             code::LEN => {
-                let v = stack_pop(&mut stack)?;
+                let v = stack_pop(stack)?;
+                if let Some(method) = metamethod(&v, "__len") {
+                    stack.push(call_value(ctx, state, &method, v, vec![])?);
+                    continue;
+                }
                 let len = match &v {
                     Value::String(s) => s.data().len(),
                     Value::Object(o) => o.get().len(),
                     Value::Array(a) => a.get().len(),
-                    _ => return Err(VMError::invalid_type("string/object/array", &v))
+                    Value::Map(m) => m.get().len(),
+                    _ => return Err(VMError::invalid_type("string/object/array/map", &v))
                 };
                 stack.push(Value::Int(len as i64));
             }
             code::IN => {
+                ctx.sandbox().check_io()?;
                 let mut str = String::new();
                 std::io::stdin().read_line(&mut str).expect("`stdin.read_line` failed");
                 if str.ends_with("\n") {
@@ -569,73 +1179,239 @@ fn execute_closure(ctx: &mut Context, state: ProgramState) -> Result<Value, VMEr
                 }
                 stack.push(Value::String(str[..].into()));
             }
-            code::OUT => println!("{}", stack_pop(&mut stack)?.to_string()),
+            code::OUT => {
+                ctx.sandbox().check_io()?;
+                let value = stack_pop(stack)?;
+                println!("{}", display(ctx, state, &value)?.to_string());
+            }
+            code::OUT_REPR => {
+                ctx.sandbox().check_io()?;
+                let value = stack_pop(stack)?;
+                println!("{}", repr(ctx, state, &value)?.to_string());
+            }
             code::LOAD_LIB => {
-                let str = next_str(&cur_func, &mut pc, program)?.into();
-                let value = load_library(ctx, &state, &str)?;
+                let str = next_str(cur_func, pc, program)?.into();
+                let value = load_library(ctx, state, &str)?;
                 stack.push(value);
             }
             _ => return Err(VMError::UnknownInstruction(code))
         }
     }
-
-    assert!(stack.len() == 1);
-
-    Ok(stack.pop().unwrap())
 }
 
 pub fn call(ctx: &mut Context, closure: &Closure, args: Vec<Value>) -> Result<Value, VMError> {
+    call_method(ctx, closure, Value::Null, args)
+}
+
+/// Calls `closure` with `receiver` as its `this`.
+pub fn call_method(ctx: &mut Context, closure: &Closure, receiver: Value, args: Vec<Value>) -> Result<Value, VMError> {
     execute_closure(ctx, ProgramState {
         program_idx: closure.program_idx,
         func_idx: closure.func_idx,
         variables: Variables::new_gc(Some(&closure.parent)),
-        args
+        args,
+        receiver
     })
 }
 
 pub fn execute_file(ctx: &mut Context, path: Rc<Path>) -> Result<Value, VMError> {
-    let program = compiler::compile_chars(fs::read_to_string(&path)?.chars())?;
+    let source = fs::read_to_string(&path)?;
+    let program = match ctx.cache().and_then(|cache| cache.load(&source)) {
+        Some(program) => program,
+        None => {
+            let (program, warnings) = compiler::compile_chars_with_warnings(source.chars())?;
+            // only sources without warnings are cached, so that these are
+            // reported on every load
+            let cache = ctx.cache().filter(|_| warnings.is_empty());
+            if let Some(cache) = cache {
+                // a cache that cannot be written only costs a recompilation next time
+                let _ = cache.store(&source, &program);
+            }
+            for warning in warnings {
+                ctx.warn(VMWarning::Library(path.to_path_buf(), warning));
+            }
+            program
+        }
+    };
     let program_idx = ctx.add_program(program, Some(path));
     execute_closure(ctx, ProgramState {
         program_idx,
         func_idx: 0,
         variables: Variables::new_gc(None),
-        args: vec![]
+        args: vec![],
+        receiver: Value::Null
     })
 }
 
+/// Library search path taken from the environment: the entries of `CUTE_PATH`,
+/// followed by the user-level library directory `~/.cute/lib`.
+pub fn default_lib_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+    if let Some(cute_path) = env::var_os("CUTE_PATH") {
+        paths.extend(env::split_paths(&cute_path).filter(|p| !p.as_os_str().is_empty()));
+    }
+    if let Some(home) = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")) {
+        paths.push(PathBuf::from(home).join(".cute").join("lib"));
+    }
+    paths
+}
+
+/// Loads the manifest in `dir` once, reporting what it ignores.
+fn load_manifest(ctx: &mut Context, dir: &Path) -> Result<Option<Rc<Manifest>>, VMError> {
+    if let Some(manifest) = ctx.get_manifest(dir) {
+        return Ok(manifest);
+    }
+    let manifest = Manifest::load(dir)?.map(Rc::new);
+    for warning in manifest.iter().flat_map(|m| m.warnings()) {
+        ctx.warn(VMWarning::Manifest(warning.clone()));
+    }
+    ctx.add_manifest(dir.to_path_buf(), manifest.clone());
+    Ok(manifest)
+}
+
+/// Loads the manifest of the package containing `dir`, i.e. the nearest
+/// `cute.toml` in `dir` or any of its ancestors.
+fn find_manifest(ctx: &mut Context, dir: &Path) -> Result<Option<Rc<Manifest>>, VMError> {
+    for dir in dir.ancestors() {
+        // a sandbox hides manifests above its library directories
+        if !ctx.sandbox().allows_path(dir) {
+            break;
+        }
+        if let Some(manifest) = load_manifest(ctx, dir)? {
+            return Ok(Some(manifest));
+        }
+    }
+    Ok(None)
+}
+
+/// Resolves a package directory to its manifest entry, or `index.cute`.
+fn resolve_package(ctx: &mut Context, dir: &Path, searched: &mut Vec<PathBuf>) -> Result<Option<PathBuf>, VMError> {
+    ctx.sandbox().check_lib_path(dir)?;
+    let entry = match load_manifest(ctx, dir)? {
+        Some(manifest) => manifest.entry(),
+        None => dir.join(crate::manifest::DEFAULT_ENTRY)
+    };
+    ctx.sandbox().check_lib_path(&entry)?;
+    if entry.is_file() {
+        return Ok(Some(entry));
+    }
+    searched.push(entry);
+    Ok(None)
+}
+
+/// Resolves module `name` (e.g. `util/strings`) under `dir`, trying
+/// `util/strings.cute` first and then the package directory `util/strings/`.
+fn resolve_module(ctx: &mut Context, dir: &Path, name: &str, searched: &mut Vec<PathBuf>) -> Result<Option<PathBuf>, VMError> {
+    let file = dir.join(name.to_owned() + ".cute");
+    ctx.sandbox().check_lib_path(&file)?;
+    if file.is_file() {
+        return Ok(Some(file));
+    }
+    searched.push(file);
+    let pkg_dir = dir.join(name);
+    if pkg_dir.is_dir() {
+        return resolve_package(ctx, &pkg_dir, searched);
+    }
+    Ok(None)
+}
+
+fn find_library(ctx: &mut Context, state: &ProgramState, name: &str) -> Result<Rc<Path>, VMError> {
+    let mut searched = vec![];
+    let program_dir = ctx.get_program_dir(state.program_idx).map(Path::to_path_buf);
+
+    let found = if Path::new(name).is_absolute() {
+        resolve_module(ctx, Path::new(""), name, &mut searched)?
+    } else {
+        // packages declared in the importing program's manifest come first
+        let (head, rest) = name.split_once('/').unwrap_or((name, ""));
+        let manifest = match &program_dir {
+            Some(dir) => find_manifest(ctx, dir)?,
+            None => None
+        };
+        let pkg_root = manifest.and_then(|m| match m.name() == Some(head) {
+            true => Some(m.dir().to_path_buf()),
+            false => m.dependency(head)
+        });
+
+        match pkg_root {
+            Some(root) if rest.is_empty() => resolve_package(ctx, &root, &mut searched)?,
+            Some(root) => resolve_module(ctx, &root, rest, &mut searched)?,
+            None => {
+                let mut found = None;
+                let dirs: Vec<_> = program_dir.into_iter()
+                    .chain(ctx.get_lib_paths().iter().cloned())
+                    .filter(|dir| ctx.sandbox().allows_path(dir))
+                    .collect();
+                for dir in dirs {
+                    found = resolve_module(ctx, &dir, name, &mut searched)?;
+                    if found.is_some() {
+                        break;
+                    }
+                }
+                found
+            }
+        }
+    };
+
+    match found {
+        Some(path) => Ok(path.canonicalize()?.into()),
+        None => Err(VMError::LibraryNotFound {
+            name: name.to_owned(),
+            searched
+        })
+    }
+}
+
 pub fn load_library(ctx: &mut Context, state: &ProgramState, name: &VMString) -> Result<Value, VMError> {
+    if let Some(lib) = ctx.get_resolved_lib(state.program_idx, name) {
+        return Ok(lib.clone());
+    }
     let value = match ctx.get_lib(name) {
-        Some(lib) => lib.clone(),
+        Some(lib) => {
+            ctx.sandbox().check_lib(name)?;
+            lib.clone()
+        }
         None => {
-            let lib_path = PathBuf::from(name.to_string() + ".cute");
-            let lib_path: Rc<Path> = if lib_path.is_absolute() {
-                lib_path
-            } else {
-                ctx.get_program_dir(state.program_idx)
-                    .ok_or(VMError::IllegalState)?
-                    .join(lib_path)
-            }.canonicalize()?.into();
+            let lib_path = match find_library(ctx, state, &name.to_string()) {
+                // built-in libraries come last, after any file of their name
+                Err(VMError::LibraryNotFound { .. }) if ctx.get_builtin_lib(name).is_some() => {
+                    ctx.sandbox().check_lib(name)?;
+                    let lib = ctx.get_builtin_lib(name).unwrap().clone();
+                    ctx.add_resolved_lib(state.program_idx, name.clone(), lib.clone());
+                    return Ok(lib);
+                }
+                res => res?
+            };
+            ctx.sandbox().check_lib_path(&lib_path)?;
             match ctx.get_file_lib(&lib_path) {
                 Some(lib) => lib.clone(),
                 None => {
-                    let lib = execute_file(ctx, lib_path.clone())?;
+                    ctx.begin_loading(lib_path.clone())?;
+                    let lib = execute_file(ctx, lib_path.clone());
+                    ctx.end_loading();
+                    let lib = lib?;
                     ctx.add_file_lib(lib_path, lib.clone());
                     lib
                 }
             }
         }
     };
+    ctx.add_resolved_lib(state.program_idx, name.clone(), value.clone());
     Ok(value)
 }
 
 pub fn execute_program(program: ProgramBundle, path: Option<Rc<Path>>) -> Result<(), VMError> {
-    let mut ctx = Context::new(program, path);
-    execute_closure(&mut ctx, ProgramState {
+    execute_main(&mut Context::new(program, path))
+}
+
+/// Executes the first program of a prepared `Context`, e.g. one with a sandbox set.
+pub fn execute_main(ctx: &mut Context) -> Result<(), VMError> {
+    execute_closure(ctx, ProgramState {
         program_idx: 0,
         func_idx: 0,
         variables: Variables::new_gc(None),
-        args: vec![]
+        args: vec![],
+        receiver: Value::Null
     })?;
     Ok(())
 }
@@ -1,2 +1,5 @@
 pub mod types;
 pub mod executor;
+pub mod manifest;
+pub mod cache;
+pub mod builtins;
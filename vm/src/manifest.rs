@@ -0,0 +1,179 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+use crate::types::VMError;
+
+pub const MANIFEST_FILE: &str = "cute.toml";
+pub const DEFAULT_ENTRY: &str = "index.cute";
+
+/// A package manifest (`cute.toml`), written in a small subset of TOML:
+///
+/// ```toml
+/// [package]
+/// name = "util"
+/// entry = "index.cute"
+///
+/// [dependencies]
+/// strings = { path = "../strings" }
+/// ```
+///
+/// Dotted keys and headers work as in TOML, e.g. `package.name = "util"` or
+/// `[dependencies.strings]` followed by `path = "../strings"`; quoted keys
+/// are not supported. Unknown sections and keys are ignored with a warning.
+pub struct Manifest {
+    dir: PathBuf,
+    name: Option<String>,
+    entry: Option<String>,
+    dependencies: HashMap<String, PathBuf>,
+    // entries ignored, as `file:line: message`
+    warnings: Vec<String>
+}
+
+impl Manifest {
+    /// Loads the manifest in `dir`, if there is one.
+    pub fn load(dir: &Path) -> Result<Option<Self>, VMError> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let source = fs::read_to_string(&path)?;
+        let mut manifest = Self::parse(dir, &source)
+            .map_err(|(line, msg)| VMError::ManifestError(
+                format!("{}:{}: {}", path.display(), line, msg)
+            ))?;
+        for warning in &mut manifest.warnings {
+            *warning = format!("{}:{}", path.display(), warning);
+        }
+        Ok(Some(manifest))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn entry(&self) -> PathBuf {
+        self.dir.join(self.entry.as_deref().unwrap_or(DEFAULT_ENTRY))
+    }
+
+    /// Root directory of the local dependency named `name`.
+    pub fn dependency(&self, name: &str) -> Option<PathBuf> {
+        self.dependencies.get(name).map(|p| self.dir.join(p))
+    }
+
+    /// Entries ignored while parsing, as `file:line: message`.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    fn parse(dir: &Path, source: &str) -> Result<Self, (usize, String)> {
+        let mut manifest = Self {
+            dir: dir.to_path_buf(),
+            name: None,
+            entry: None,
+            dependencies: HashMap::new(),
+            warnings: vec![]
+        };
+        let mut section = vec![];
+        // line and path of each dependency, which may be given by several lines
+        let mut dependencies: HashMap<String, (usize, Option<String>)> = HashMap::new();
+
+        for (idx, line) in source.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[') {
+                let name = name.strip_suffix(']')
+                    .ok_or((line_no, "unclosed section header".to_owned()))?;
+                section = split_key(name).map_err(|e| (line_no, e))?;
+                continue;
+            }
+
+            let (key, value) = line.split_once('=')
+                .ok_or((line_no, "expected `key = value`".to_owned()))?;
+            let mut key_path = section.clone();
+            key_path.extend(split_key(key).map_err(|e| (line_no, e))?);
+            let value = value.trim();
+
+            let result = match key_path.as_slice() {
+                ["dependencies", name] => match value.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+                    // an inline table, its fields being dotted keys of the dependency
+                    Some(table) => {
+                        dependencies.entry(name.to_string()).or_insert((line_no, None));
+                        table.split(',').map(str::trim).filter(|e| !e.is_empty()).try_for_each(|entry| {
+                            let (field, value) = entry.split_once('=')
+                                .ok_or(format!("expected `key = value`, got `{}`", entry))?;
+                            let mut field_path = key_path.clone();
+                            field_path.extend(split_key(field)?);
+                            manifest.set(&field_path, value.trim(), &mut dependencies, line_no)
+                        })
+                    }
+                    None => manifest.set(&key_path, value, &mut dependencies, line_no)
+                }
+                _ => manifest.set(&key_path, value, &mut dependencies, line_no)
+            };
+            result.map_err(|e| (line_no, e))?;
+        }
+
+        for (name, (line_no, path)) in dependencies {
+            let path = path.ok_or((line_no, format!("dependency `{}` without `path`", name)))?;
+            manifest.dependencies.insert(name, path.into());
+        }
+        Ok(manifest)
+    }
+
+    /// Sets the entry at `key_path`, e.g. `["package", "name"]`, or ignores
+    /// it with a warning if unknown.
+    fn set(
+        &mut self,
+        key_path: &[&str],
+        value: &str,
+        dependencies: &mut HashMap<String, (usize, Option<String>)>,
+        line_no: usize
+    ) -> Result<(), String> {
+        match key_path {
+            ["package", "name"] => self.name = Some(parse_string(value)?),
+            ["package", "entry"] => self.entry = Some(parse_string(value)?),
+            ["dependencies", name] | ["dependencies", name, "path"] => {
+                let path = parse_string(value)?;
+                dependencies.entry(name.to_string()).or_insert((line_no, None)).1 = Some(path);
+            }
+            _ => self.warnings.push(format!("{}: unknown key `{}` ignored", line_no, key_path.join(".")))
+        }
+        Ok(())
+    }
+}
+
+/// Splits a possibly dotted key or section name into its parts.
+fn split_key(key: &str) -> Result<Vec<&str>, String> {
+    key.split('.').map(str::trim).map(|part| match part.is_empty() || part.contains(['"', '\'', ' ']) {
+        true => Err(format!("unsupported key `{}`", key.trim())),
+        false => Ok(part)
+    }).collect()
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (idx, char) in line.char_indices() {
+        match (quote, char) {
+            (None, '#') => return &line[..idx],
+            (None, '"' | '\'') => quote = Some(char),
+            (Some(q), _) if q == char => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_string(value: &str) -> Result<String, String> {
+    for quote in ['"', '\''] {
+        if let Some(str) = value.strip_prefix(quote).and_then(|v| v.strip_suffix(quote)) {
+            return Ok(str.to_owned());
+        }
+    }
+    Err(format!("expected a string, got `{}`", value))
+}
@@ -1,7 +1,8 @@
-use std::{collections::HashMap, rc::Rc, borrow::Borrow, io, path::Path};
+use std::{collections::{HashMap, HashSet, hash_map::DefaultHasher}, rc::Rc, borrow::Borrow, cmp::Ordering, hash::{Hash, Hasher}, io, fmt, ops::Deref, path::{Component, Path, PathBuf}};
 use gc::{Trace, Finalize, Gc, GcCell, GcCellRef, GcCellRefMut};
 use bytecode::program::ProgramBundle;
-use compiler::parser::ParserError;
+use compiler::{parser::ParserError, lexer::LexerWarning};
+use crate::{cache::CompileCache, manifest::Manifest, builtins};
 
 #[derive(Debug)]
 pub enum VMError {
@@ -11,22 +12,200 @@ pub enum VMError {
     ConstantIndexOutOfBound,
     ConstantNotString,
     BadStack,
-    InvalidType { expected: String, got: String },
+    // `arg` is set when a native function got the value as an argument
+    InvalidType { expected: String, got: String, arg: Option<ArgPos> },
     DivideByZeroError,
     ArrayIndexOutOfBound,
     SuperDoesNotExist,
     ObjectLocked,
-    IllegalFunctionArguments,
+    PrototypeCycle,
+    // a map key that is not immutable, described by `0`
+    UnhashableKey(String),
+    // a native function called with `got` arguments, expecting `min ..= max`
+    IllegalFunctionArguments { function: String, min: usize, max: Option<usize>, got: usize },
+    TooManyArguments { expected: usize, got: usize },
     IllegalState,
+    SandboxViolation(String),
+    LibraryNotFound { name: String, searched: Vec<PathBuf> },
+    CircularImport(Vec<PathBuf>),
+    ManifestError(String),
+    // an error of a kind of its own, raised by a native function
+    Custom { kind: String, message: String, payload: Value },
+    // a value raised by `@!`, kept as it is for `catch`
+    Thrown(Value),
+    Unwind(Box<Unwind>),
+    // an `Unwind` back in its frame after its loop ended, `^` if `is_break`
+    LoopNotRunning { is_break: bool },
     CompilerError(ParserError),
     IOError(io::Error)
 }
 
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FunctionIndexOutOfBound => write!(f, "function index out of bound"),
+            Self::PCIndexOutOfBound => write!(f, "program counter out of bound"),
+            Self::UnknownInstruction(code) => write!(f, "unknown instruction {:#x}", code),
+            Self::ConstantIndexOutOfBound => write!(f, "constant index out of bound"),
+            Self::ConstantNotString => write!(f, "constant is not a string"),
+            Self::BadStack => write!(f, "bad stack"),
+            Self::InvalidType { expected, got, arg } => {
+                write!(f, "invalid type: expected {}, got {}", expected, got)?;
+                match arg {
+                    Some(arg) => write!(f, " for argument {} of `{}`", arg.index, arg.function),
+                    None => Ok(())
+                }
+            }
+            Self::DivideByZeroError => write!(f, "divide by zero"),
+            Self::ArrayIndexOutOfBound => write!(f, "array index out of bound"),
+            Self::SuperDoesNotExist => write!(f, "super does not exist"),
+            Self::ObjectLocked => write!(f, "object is locked"),
+            Self::PrototypeCycle => write!(f, "object would be its own prototype"),
+            Self::UnhashableKey(got) => write!(f, "unhashable map key: {}", got),
+            Self::IllegalFunctionArguments { function, min, max, got } => {
+                write!(f, "illegal function arguments: `{}` expects ", function)?;
+                match max {
+                    Some(1) if *min == 1 => write!(f, "1 argument")?,
+                    Some(max) if max == min => write!(f, "{} arguments", min)?,
+                    Some(max) => write!(f, "{} to {} arguments", min, max)?,
+                    None => write!(f, "at least {} arguments", min)?
+                }
+                write!(f, ", got {}", got)
+            }
+            Self::TooManyArguments { expected, got } =>
+                write!(f, "too many arguments: expected at most {}, got {}", expected, got),
+            Self::IllegalState => write!(f, "illegal state"),
+            Self::SandboxViolation(msg) => write!(f, "sandbox violation: {}", msg),
+            Self::LibraryNotFound { name, searched } => {
+                write!(f, "library `{}` not found, searched:", name)?;
+                for path in searched {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                Ok(())
+            }
+            Self::CircularImport(chain) => {
+                write!(f, "circular import: ")?;
+                for (idx, path) in chain.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, " -> ")?;
+                    }
+                    write!(f, "{}", path.display())?;
+                }
+                Ok(())
+            }
+            Self::ManifestError(msg) => write!(f, "bad manifest: {}", msg),
+            Self::Custom { kind, message, .. } => write!(f, "{}: {}", kind, message),
+            Self::Thrown(value) => match thrown_kind(value) {
+                // e.g. a caught error thrown again
+                Some((kind, message)) => write!(f, "{}: {}", kind, message),
+                None => write!(f, "uncaught exception: {}", value.repr())
+            }
+            Self::Unwind(unwind) => match unwind.value {
+                Some(_) => write!(f, "`^` outside of its running loop"),
+                None => write!(f, "`^^` outside of its running loop")
+            }
+            Self::LoopNotRunning { is_break: true } => write!(f, "`^` outside of its running loop"),
+            Self::LoopNotRunning { is_break: false } => write!(f, "`^^` outside of its running loop"),
+            Self::CompilerError(e) => write!(f, "compiler error: {:?}", e),
+            Self::IOError(e) => write!(f, "IO error: {}", e)
+        }
+    }
+}
+
+/// The `kind` and `message` of a thrown error object, if it has a string
+/// `kind`.
+fn thrown_kind(value: &Value) -> Option<(String, String)> {
+    let obj = value.as_obj().ok()?.get();
+    match obj.get(&VMString::from("kind")) {
+        Some(Value::String(kind)) => Some((
+            kind.to_string(),
+            obj.get(&VMString::from("message")).map_or(String::new(), Value::to_string)
+        )),
+        _ => None
+    }
+}
+
 impl VMError {
     pub fn invalid_type(expected: &str, got: &Value) -> Self {
         Self::InvalidType {
             expected: expected.to_owned(),
-            got: got.type_to_str().to_owned()
+            got: got.type_to_str().to_owned(),
+            arg: None
+        }
+    }
+
+    pub fn illegal_arguments(function: &str, min: usize, max: Option<usize>, got: usize) -> Self {
+        Self::IllegalFunctionArguments { function: function.to_owned(), min, max, got }
+    }
+
+    pub fn custom(kind: &str, message: impl Into<String>, payload: Value) -> Self {
+        Self::Custom { kind: kind.to_owned(), message: message.into(), payload }
+    }
+
+    /// Marks an `InvalidType` error as caused by argument `index` (from 0)
+    /// of the native function `function`.
+    pub fn at_arg(self, function: &str, index: usize) -> Self {
+        match self {
+            Self::InvalidType { expected, got, .. } => Self::InvalidType {
+                expected,
+                got,
+                arg: Some(ArgPos { function: function.to_owned(), index })
+            },
+            e => e
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        match self {
+            Self::FunctionIndexOutOfBound => "FunctionIndexOutOfBound",
+            Self::PCIndexOutOfBound => "PCIndexOutOfBound",
+            Self::UnknownInstruction(_) => "UnknownInstruction",
+            Self::ConstantIndexOutOfBound => "ConstantIndexOutOfBound",
+            Self::ConstantNotString => "ConstantNotString",
+            Self::BadStack => "BadStack",
+            Self::InvalidType { .. } => "InvalidType",
+            Self::DivideByZeroError => "DivideByZeroError",
+            Self::ArrayIndexOutOfBound => "ArrayIndexOutOfBound",
+            Self::SuperDoesNotExist => "SuperDoesNotExist",
+            Self::ObjectLocked => "ObjectLocked",
+            Self::PrototypeCycle => "PrototypeCycle",
+            Self::UnhashableKey(_) => "UnhashableKey",
+            Self::IllegalFunctionArguments { .. } => "IllegalFunctionArguments",
+            Self::TooManyArguments { .. } => "TooManyArguments",
+            Self::IllegalState => "IllegalState",
+            Self::SandboxViolation(_) => "SandboxViolation",
+            Self::LibraryNotFound { .. } => "LibraryNotFound",
+            Self::CircularImport(_) => "CircularImport",
+            Self::ManifestError(_) => "ManifestError",
+            Self::Custom { kind, .. } => kind,
+            Self::Thrown(_) => "Thrown",
+            Self::Unwind(_) => "Unwind",
+            Self::LoopNotRunning { .. } => "LoopNotRunning",
+            Self::CompilerError(_) => "CompilerError",
+            Self::IOError(_) => "IOError"
+        }
+    }
+
+    /// Whether a `catch` may handle the error. `break` and `continue`
+    /// travelling to their loop only run `finally` code on the way.
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self, Self::Unwind(_))
+    }
+
+    /// The value a `catch` receives: the thrown value itself, or an error
+    /// object with `kind` and `message` fields, plus `payload` for `Custom`.
+    pub fn into_value(self) -> Value {
+        match self {
+            Self::Thrown(value) => value,
+            Self::Custom { kind, message, payload } => Value::new_obj(HashMap::from([
+                ("kind".into(), Value::String(kind[..].into())),
+                ("message".into(), Value::String(message[..].into())),
+                ("payload".into(), payload)
+            ])),
+            e => Value::new_obj(HashMap::from([
+                ("kind".into(), Value::String(e.kind().into())),
+                ("message".into(), Value::String(e.to_string()[..].into()))
+            ]))
         }
     }
 }
@@ -43,6 +222,54 @@ impl From<io::Error> for VMError {
     }
 }
 
+/// A problem found while running that does not stop the program, collected
+/// by the `Context` for its embedder, see `Context::take_warnings`.
+#[derive(Debug)]
+pub enum VMWarning {
+    // an entry of a package manifest that is ignored, as `file:line: message`
+    Manifest(String),
+    // found while compiling the library at that path
+    Library(PathBuf, LexerWarning)
+}
+
+impl fmt::Display for VMWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Manifest(msg) => write!(f, "manifest {}", msg),
+            Self::Library(path, warning) => write!(f, "{}: {}", path.display(), warning)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ArgPos {
+    pub function: String,
+    pub index: usize
+}
+
+/// A `break` or `continue` travelling up the call stack to the frame
+/// running its loop.
+pub struct Unwind {
+    // variables of the target frame
+    pub target: Gc<Variables>,
+    // nesting depth of the loop within the target function
+    pub depth: usize,
+    // code position the loop continues at, telling it from other loops at
+    // that depth
+    pub start: usize,
+    // the loop's value for `break`, `None` for `continue`
+    pub value: Option<Value>
+}
+
+impl fmt::Debug for Unwind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Some(_) => write!(f, "Break({})", self.depth),
+            None => write!(f, "Continue({})", self.depth)
+        }
+    }
+}
+
 #[derive(Trace, Finalize)]
 pub struct Lockable<T: Trace + Finalize + 'static> {
     data: GcCell<T>,
@@ -132,6 +359,12 @@ impl From<&[u16]> for VMString {
     }
 }
 
+impl From<Vec<u16>> for VMString {
+    fn from(data: Vec<u16>) -> Self {
+        Self(data.into())
+    }
+}
+
 impl From<&str> for VMString {
     fn from(s: &str) -> Self {
         Self(s.encode_utf16().collect())
@@ -150,6 +383,202 @@ impl ToString for VMString {
     }
 }
 
+/// Compares an int with a float exactly, without rounding the int to float
+/// precision. `None` if the float is NaN.
+fn cmp_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        return None;
+    }
+    // i64 covers [-2^63, 2^63)
+    if f >= 9223372036854775808.0 {
+        return Some(Ordering::Less);
+    }
+    if f < -9223372036854775808.0 {
+        return Some(Ordering::Greater);
+    }
+    let trunc = f.trunc();
+    match i.cmp(&(trunc as i64)) {
+        Ordering::Equal => 0.0.partial_cmp(&(f - trunc)),
+        ord => Some(ord)
+    }
+}
+
+/// Converts a value in place of the default when it returns a string.
+pub type FormatHook<'a> = dyn FnMut(&Value, bool) -> Result<Option<String>, VMError> + 'a;
+
+fn write_value(out: &mut String, value: &Value, repr: bool, seen: &mut Vec<*const ()>, custom: &mut FormatHook) -> Result<(), VMError> {
+    if let Some(str) = custom(value, repr)? {
+        out.push_str(&str);
+        return Ok(());
+    }
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Int(i) => out.push_str(&i.to_string()),
+        Value::Float(f) => out.push_str(&float_to_string(*f)),
+        Value::Bool(b) => out.push_str(&b.to_string()),
+        Value::String(s) if repr => write_quoted(out, &s.to_string()),
+        Value::String(s) => out.push_str(&s.to_string()),
+        Value::Object(o) => {
+            let ptr = addr(o);
+            if seen.contains(&ptr) {
+                out.push_str("{...}");
+                return Ok(());
+            }
+            // copied out, as `custom` may run code changing the object
+            let mut fields: Vec<_> = o.get().iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect();
+            fields.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+            seen.push(ptr);
+            out.push('{');
+            for (key, value) in &fields {
+                out.push(' ');
+                if is_ident(key) {
+                    out.push_str(key);
+                } else {
+                    write_quoted(out, key);
+                }
+                out.push_str(" = ");
+                write_value(out, value, true, seen, custom)?;
+                out.push(';');
+            }
+            out.push_str(if fields.is_empty() { "}" } else { " }" });
+            seen.pop();
+        }
+        Value::Array(a) => {
+            let ptr = addr(a);
+            if seen.contains(&ptr) {
+                out.push_str("[...]");
+                return Ok(());
+            }
+            let items = a.get().clone();
+            seen.push(ptr);
+            out.push('[');
+            for (idx, item) in items.iter().enumerate() {
+                if idx > 0 {
+                    out.push_str(", ");
+                }
+                write_value(out, item, true, seen, custom)?;
+            }
+            out.push(']');
+            seen.pop();
+        }
+        Value::Map(m) => {
+            let ptr = addr(m);
+            if seen.contains(&ptr) {
+                out.push_str("map[...]");
+                return Ok(());
+            }
+            let mut entries: Vec<_> = m.get().iter()
+                .map(|(k, v)| (k.value().repr(), v.clone()))
+                .collect();
+            entries.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+            seen.push(ptr);
+            out.push_str("map[");
+            for (idx, (key, value)) in entries.iter().enumerate() {
+                if idx > 0 {
+                    out.push_str(", ");
+                }
+                out.push_str(key);
+                out.push_str(" => ");
+                write_value(out, value, true, seen, custom)?;
+            }
+            out.push(']');
+            seen.pop();
+        }
+        Value::Closure(_) => out.push_str("[closure]"),
+        Value::NativeFunction(_) => out.push_str("[native function]")
+    }
+    Ok(())
+}
+
+/// Identifies the value behind `gc`, e.g. to detect cycles.
+fn addr<T: Trace + ?Sized>(gc: &Gc<T>) -> *const () {
+    &**gc as *const T as *const ()
+}
+
+/// `seen` holds the pairs of arrays, objects or maps being compared further up,
+/// which are taken as equal when met again, as nothing found them unequal.
+fn deep_eq(v1: &Value, v2: &Value, seen: &mut Vec<(*const (), *const ())>) -> bool {
+    let pair = match (v1, v2) {
+        (Value::Array(a1), Value::Array(a2)) => (addr(a1), addr(a2)),
+        (Value::Object(o1), Value::Object(o2)) => (addr(o1), addr(o2)),
+        (Value::Map(m1), Value::Map(m2)) => (addr(m1), addr(m2)),
+        _ => return v1.cmp_eq(v2)
+    };
+    if pair.0 == pair.1 || seen.contains(&pair) {
+        return true;
+    }
+    seen.push(pair);
+    let equal = match (v1, v2) {
+        (Value::Array(a1), Value::Array(a2)) => {
+            let (items1, items2) = (a1.get(), a2.get());
+            items1.len() == items2.len()
+                && items1.iter().zip(items2.iter()).all(|(i1, i2)| deep_eq(i1, i2, seen))
+        }
+        (Value::Object(o1), Value::Object(o2)) => {
+            let same_proto = match (o1.proto(), o2.proto()) {
+                (Some(p1), Some(p2)) => Gc::ptr_eq(&p1, &p2),
+                (p1, p2) => p1.is_none() && p2.is_none()
+            };
+            let (fields1, fields2) = (o1.get(), o2.get());
+            same_proto && fields1.len() == fields2.len()
+                && fields1.iter().all(|(key, f1)| match fields2.get(key) {
+                    Some(f2) => deep_eq(f1, f2, seen),
+                    None => false
+                })
+        }
+        (Value::Map(m1), Value::Map(m2)) => {
+            let (entries1, entries2) = (m1.get(), m2.get());
+            entries1.len() == entries2.len()
+                && entries1.iter().all(|(key, e1)| match entries2.get(key) {
+                    Some(e2) => deep_eq(e1, e2, seen),
+                    None => false
+                })
+        }
+        _ => unreachable!()
+    };
+    seen.pop();
+    equal
+}
+
+/// The shortest form reading back as the same float, never as an int.
+/// Like JavaScript, magnitudes from 1e21 and below 1e-6 use an exponent.
+fn float_to_string(f: f64) -> String {
+    if f.is_finite() && f != 0.0 && !(1e-6..1e21).contains(&f.abs()) {
+        return format!("{:e}", f);
+    }
+    let str = f.to_string();
+    if f.is_finite() && !str.contains('.') {
+        str + ".0"
+    } else {
+        str
+    }
+}
+
+fn write_quoted(out: &mut String, str: &str) {
+    out.push('"');
+    for char in str.chars() {
+        match char {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0' => out.push_str("\\0"),
+            char if char.is_control() => out.push_str(&format!("\\u{{{:x}}}", char as u32)),
+            char => out.push(char)
+        }
+    }
+    out.push('"');
+}
+
+fn is_ident(str: &str) -> bool {
+    let mut chars = str.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
 #[derive(Clone, Trace, Finalize)]
 pub struct Closure {
     pub parent: Gc<Variables>,
@@ -157,8 +586,174 @@ pub struct Closure {
     pub func_idx: usize
 }
 
-type ObjectRef = Gc<Lockable<HashMap<VMString, Value>>>;
+/// An object's own fields, with an optional prototype to look up the
+/// fields it does not have.
+#[derive(Trace, Finalize)]
+pub struct Object {
+    fields: Lockable<HashMap<VMString, Value>>,
+    proto: GcCell<Option<ObjectRef>>
+}
+
+impl Object {
+    pub fn new(fields: HashMap<VMString, Value>, locked: bool) -> Self {
+        Self {
+            fields: Lockable::new(fields, locked),
+            proto: GcCell::new(None)
+        }
+    }
+
+    pub fn proto(&self) -> Option<ObjectRef> {
+        self.proto.borrow().clone()
+    }
+
+    /// Sets the prototype of `obj`, which must not end up in its own chain.
+    pub fn set_proto(obj: &ObjectRef, proto: Option<ObjectRef>) -> Result<(), VMError> {
+        if obj.is_locked() {
+            return Err(VMError::ObjectLocked);
+        }
+        let mut next = proto.clone();
+        while let Some(ancestor) = next {
+            if Gc::ptr_eq(&ancestor, obj) {
+                return Err(VMError::PrototypeCycle);
+            }
+            next = ancestor.proto();
+        }
+        *obj.proto.borrow_mut() = proto;
+        Ok(())
+    }
+
+    /// A locked copy of the object, with the same prototype.
+    pub fn locked_copy(&self) -> Self {
+        Self {
+            fields: Lockable::new(self.get().clone(), true),
+            proto: GcCell::new(self.proto())
+        }
+    }
+
+    /// Looks up a field of the object or, failing that, of its prototypes.
+    pub fn lookup(&self, key: &[u16]) -> Option<Value> {
+        if let Some(value) = self.get().get(key) {
+            return Some(value.clone());
+        }
+        let mut next = self.proto();
+        while let Some(obj) = next {
+            if let Some(value) = obj.get().get(key) {
+                return Some(value.clone());
+            }
+            next = obj.proto();
+        }
+        None
+    }
+}
+
+impl Deref for Object {
+    type Target = Lockable<HashMap<VMString, Value>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.fields
+    }
+}
+
+/// A value usable as a map key: null, a bool, a number other than NaN, a
+/// string, or a locked array or object holding only such values. Keys are
+/// compared with `Value::deep_eq`, so `1` and `1.0` are the same key.
+#[derive(Clone, Trace, Finalize)]
+pub struct MapKey(Value);
+
+impl MapKey {
+    pub fn new(value: Value) -> Result<Self, VMError> {
+        check_hashable(&value)?;
+        Ok(Self(value))
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.deep_eq(&other.0)
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_value(&self.0, state);
+    }
+}
+
+fn check_hashable(value: &Value) -> Result<(), VMError> {
+    match value {
+        Value::Null | Value::Int(_) | Value::Bool(_) | Value::String(_) => Ok(()),
+        Value::Float(f) if f.is_nan() => Err(VMError::UnhashableKey("NaN".to_owned())),
+        Value::Float(_) => Ok(()),
+        // a locked array or object cannot contain itself, as it got all of
+        // its contents before it existed
+        Value::Array(a) if a.is_locked() => a.get().iter().try_for_each(check_hashable),
+        Value::Object(o) if o.is_locked() => o.get().values().try_for_each(check_hashable),
+        Value::Array(_) | Value::Object(_) =>
+            Err(VMError::UnhashableKey(format!("unlocked {}", value.type_to_str()))),
+        _ => Err(VMError::UnhashableKey(value.type_to_str().to_owned()))
+    }
+}
+
+fn hash_value<H: Hasher>(value: &Value, state: &mut H) {
+    match value {
+        Value::Null => 0u8.hash(state),
+        Value::Bool(b) => {
+            1u8.hash(state);
+            b.hash(state);
+        }
+        Value::Int(i) => {
+            2u8.hash(state);
+            i.hash(state);
+        }
+        // whole floats hash like the ints they equal
+        Value::Float(f) if f.fract() == 0.0 && cmp_int_float(*f as i64, *f) == Some(Ordering::Equal) => {
+            2u8.hash(state);
+            (*f as i64).hash(state);
+        }
+        Value::Float(f) => {
+            3u8.hash(state);
+            f.to_bits().hash(state);
+        }
+        Value::String(s) => {
+            4u8.hash(state);
+            s.hash(state);
+        }
+        Value::Array(a) => {
+            5u8.hash(state);
+            let items = a.get();
+            items.len().hash(state);
+            for item in items.iter() {
+                hash_value(item, state);
+            }
+        }
+        Value::Object(o) => {
+            6u8.hash(state);
+            let fields = o.get();
+            fields.len().hash(state);
+            // independent of the order of the fields
+            let mut sum = 0u64;
+            for (key, value) in fields.iter() {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hash_value(value, &mut hasher);
+                sum = sum.wrapping_add(hasher.finish());
+            }
+            sum.hash(state);
+        }
+        // not hashable, see `check_hashable`
+        _ => 7u8.hash(state)
+    }
+}
+
+type ObjectRef = Gc<Object>;
 type ArrayRef = Gc<Lockable<Vec<Value>>>;
+type MapRef = Gc<Lockable<HashMap<MapKey, Value>>>;
 
 #[derive(Clone, Trace, Finalize)]
 pub enum Value {
@@ -169,6 +764,7 @@ pub enum Value {
     String(VMString),
     Object(ObjectRef),
     Array(ArrayRef),
+    Map(MapRef),
     Closure(Closure),
     NativeFunction(
         #[unsafe_ignore_trace]
@@ -176,6 +772,12 @@ pub enum Value {
     )
 }
 
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_string())
+    }
+}
+
 impl Value {
     pub fn type_to_str(&self) -> &'static str {
         match self {
@@ -186,17 +788,18 @@ impl Value {
             Self::String(_) => "string",
             Self::Object(_) => "object",
             Self::Array(_) => "array",
+            Self::Map(_) => "map",
             Self::Closure(_) => "closure",
             Self::NativeFunction(_) => "native function"
         }
     }
 
     pub fn new_obj(o: HashMap<VMString, Value>) -> Self {
-        Self::Object(Gc::new(Lockable::new(o, false)))
+        Self::Object(Gc::new(Object::new(o, false)))
     }
 
     pub fn new_locked_obj(o: HashMap<VMString, Value>) -> Self {
-        Self::Object(Gc::new(Lockable::new(o, true)))
+        Self::Object(Gc::new(Object::new(o, true)))
     }
 
     pub fn new_arr(a: Vec<Value>) -> Self {
@@ -207,6 +810,10 @@ impl Value {
         Self::Array(Gc::new(Lockable::new(a, true)))
     }
 
+    pub fn new_map() -> Self {
+        Self::Map(Gc::new(Lockable::new(HashMap::new(), false)))
+    }
+
     pub fn as_int(&self) -> Result<i64, VMError> {
         match self {
             Value::Int(i) => Ok(*i),
@@ -270,6 +877,13 @@ impl Value {
         }
     }
 
+    pub fn as_map(&self) -> Result<&MapRef, VMError> {
+        match self {
+            Value::Map(m) => Ok(m),
+            _ => Err(VMError::invalid_type("map", self))
+        }
+    }
+
     pub fn as_closure(&self) -> Result<&Closure, VMError> {
         match self {
             Value::Closure(c) => Ok(c),
@@ -277,6 +891,8 @@ impl Value {
         }
     }
 
+    /// Ints and floats compare by numeric value, so `1 == 1.0`; other
+    /// values of different types are never equal.
     pub fn cmp_eq(&self, other: &Value) -> bool {
         match self {
             Value::Null => match other {
@@ -285,9 +901,11 @@ impl Value {
             }
             Value::Int(v) => match other {
                 Value::Int(v2) => v == v2,
+                Value::Float(v2) => cmp_int_float(*v, *v2) == Some(Ordering::Equal),
                 _ => false
             }
             Value::Float(v) => match other {
+                Value::Int(v2) => cmp_int_float(*v2, *v) == Some(Ordering::Equal),
                 Value::Float(v2) => v == v2,
                 _ => false
             }
@@ -307,6 +925,10 @@ impl Value {
                 Value::Array(v2) => Gc::ptr_eq(v, v2),
                 _ => false
             }
+            Value::Map(v) => match other {
+                Value::Map(v2) => Gc::ptr_eq(v, v2),
+                _ => false
+            }
             Value::Closure(v) => match other {
                 Value::Closure(v2) =>
                     v.func_idx == v2.func_idx && Gc::ptr_eq(&v.parent, &v2.parent),
@@ -319,10 +941,22 @@ impl Value {
         }
     }
 
+    /// Compares arrays, objects and maps by their contents, recursively, and
+    /// other values like `cmp_eq`. Objects must also share their prototype.
+    pub fn deep_eq(&self, other: &Value) -> bool {
+        deep_eq(self, other, &mut vec![])
+    }
+
     pub fn cmp_gt(&self, other: &Value) -> Result<bool, VMError> {
         Ok(match self {
-            Value::Int(i) => *i > other.as_int()?,
-            Value::Float(f) => *f > other.as_float()?,
+            Value::Int(i) => match other {
+                Value::Float(f) => cmp_int_float(*i, *f) == Some(Ordering::Greater),
+                _ => *i > other.as_int()?
+            }
+            Value::Float(f) => match other {
+                Value::Int(i) => cmp_int_float(*i, *f) == Some(Ordering::Less),
+                _ => *f > other.as_float()?
+            }
             Value::String(s) => s.data() > other.as_str()?.data(),
             _ => return Err(VMError::invalid_type("int/float/string", self))
         })
@@ -330,34 +964,47 @@ impl Value {
 
     pub fn cmp_lt(&self, other: &Value) -> Result<bool, VMError> {
         Ok(match self {
-            Value::Int(i) => *i < other.as_int()?,
-            Value::Float(f) => *f < other.as_float()?,
+            Value::Int(i) => match other {
+                Value::Float(f) => cmp_int_float(*i, *f) == Some(Ordering::Less),
+                _ => *i < other.as_int()?
+            }
+            Value::Float(f) => match other {
+                Value::Int(i) => cmp_int_float(*i, *f) == Some(Ordering::Greater),
+                _ => *f < other.as_float()?
+            }
             Value::String(s) => s.data() < other.as_str()?.data(),
             _ => return Err(VMError::invalid_type("int/float/string", self))
         })
     }
 
     pub fn to_string(&self) -> String {
-        match self {
-            Value::Null => "null".to_owned(),
-            Value::Int(i) => i.to_string(),
-            Value::Float(f) => f.to_string(),
-            Value::Bool(b) => b.to_string(),
-            Value::String(s) => s.to_string(),
-            Value::Object(_) => "[object]".to_owned(),
-            Value::Array(_) => "[array]".to_owned(),
-            Value::Closure(_) => "[closure]".to_owned(),
-            Value::NativeFunction(_) => "[native function]".to_owned()
-        }
+        self.format(false, &mut |_, _| Ok(None)).unwrap_or_default()
+    }
+
+    /// Like `to_string`, but strings are quoted as literals.
+    pub fn repr(&self) -> String {
+        self.format(true, &mut |_, _| Ok(None)).unwrap_or_default()
     }
 
-    pub fn extract_args<const N: usize>(args: Vec<Value>) -> Result<[Value; N], VMError> {
-        args.try_into().map_err(|_| VMError::IllegalFunctionArguments)
+    /// Converts arrays and objects recursively, quoting the strings in them.
+    /// One met again inside itself is written `[...]` or `{...}`. `custom`
+    /// may convert any object instead, e.g. by a metamethod.
+    pub fn format(&self, repr: bool, custom: &mut FormatHook) -> Result<String, VMError> {
+        let mut out = String::new();
+        write_value(&mut out, self, repr, &mut vec![], custom)?;
+        Ok(out)
     }
 
-    pub fn extract_args_and_optional<const N: usize, const M: usize>(mut args: Vec<Value>) -> Result<([Value; N], [Option<Value>; M]), VMError> {
+    /// Takes exactly `N` arguments of the native function `function`, which
+    /// is named in the error otherwise. The variants below work alike.
+    pub fn extract_args<const N: usize>(function: &str, args: Vec<Value>) -> Result<[Value; N], VMError> {
+        let got = args.len();
+        args.try_into().map_err(|_| VMError::illegal_arguments(function, N, Some(N), got))
+    }
+
+    pub fn extract_args_and_optional<const N: usize, const M: usize>(function: &str, mut args: Vec<Value>) -> Result<([Value; N], [Option<Value>; M]), VMError> {
         if args.len() < N || args.len() > N + M {
-            return Err(VMError::IllegalFunctionArguments);
+            return Err(VMError::illegal_arguments(function, N, Some(N + M), args.len()));
         }
         let mut arr: Vec<_> = args.drain(N..).map(|v| Some(v)).collect();
         arr.resize(M, None);
@@ -367,9 +1014,9 @@ impl Value {
         ))
     }
 
-    pub fn extract_args_and_array<const N: usize>(mut args: Vec<Value>) -> Result<([Value; N], Vec<Value>), VMError> {
+    pub fn extract_args_and_array<const N: usize>(function: &str, mut args: Vec<Value>) -> Result<([Value; N], Vec<Value>), VMError> {
         if args.len() < N {
-            return Err(VMError::IllegalFunctionArguments);
+            return Err(VMError::illegal_arguments(function, N, None, args.len()));
         }
         let arr = args.drain(N..).collect();
         Ok((
@@ -379,19 +1026,165 @@ impl Value {
     }
 }
 
+/// Capabilities granted to the scripts running in a `Context`.
+///
+/// `None` for `lib_dirs` or `visible_libs` means no restriction.
+pub struct Sandbox {
+    io_allowed: bool,
+    lib_dirs: Option<Vec<PathBuf>>,
+    visible_libs: Option<HashSet<VMString>>
+}
+
+impl Sandbox {
+    pub fn unrestricted() -> Self {
+        Self {
+            io_allowed: true,
+            lib_dirs: None,
+            visible_libs: None
+        }
+    }
+
+    pub fn restricted() -> Self {
+        Self {
+            io_allowed: false,
+            lib_dirs: Some(vec![]),
+            visible_libs: Some(HashSet::new())
+        }
+    }
+
+    pub fn set_io_allowed(&mut self, allowed: bool) {
+        self.io_allowed = allowed;
+    }
+
+    /// Allows file libraries under `dir` to be loaded. The directory is
+    /// canonicalized so that it can be compared with canonical library paths.
+    pub fn add_lib_dir(&mut self, dir: &Path) -> Result<(), VMError> {
+        let dir = dir.canonicalize()?;
+        self.lib_dirs.get_or_insert_with(Vec::new).push(dir);
+        Ok(())
+    }
+
+    pub fn add_visible_lib(&mut self, name: VMString) {
+        self.visible_libs.get_or_insert_with(HashSet::new).insert(name);
+    }
+
+    pub fn check_io(&self) -> Result<(), VMError> {
+        if self.io_allowed {
+            Ok(())
+        } else {
+            Err(VMError::SandboxViolation("console IO is not allowed".to_owned()))
+        }
+    }
+
+    /// Whether file libraries may be looked for at `path`. Paths are
+    /// compared lexically, so this can be asked before touching the
+    /// filesystem; `check_lib_path` on the canonical path still follows.
+    pub fn allows_path(&self, path: &Path) -> bool {
+        match &self.lib_dirs {
+            Some(dirs) => {
+                let path = normalize_path(path);
+                dirs.iter().any(|dir| path.starts_with(dir))
+            }
+            None => true
+        }
+    }
+
+    pub fn check_lib_path(&self, path: &Path) -> Result<(), VMError> {
+        match &self.lib_dirs {
+            Some(_) if !self.allows_path(path) =>
+                Err(VMError::SandboxViolation(
+                    format!("loading library file {:?} is not allowed", path)
+                )),
+            _ => Ok(())
+        }
+    }
+
+    pub fn check_lib(&self, name: &VMString) -> Result<(), VMError> {
+        match &self.visible_libs {
+            Some(libs) if !libs.contains(name) =>
+                Err(VMError::SandboxViolation(
+                    format!("library `{}` is not visible", name.to_string())
+                )),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Makes `path` absolute and removes `.` and `..` without following links.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = match path.is_absolute() {
+        true => PathBuf::new(),
+        false => std::env::current_dir().unwrap_or_default()
+    };
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component)
+        }
+    }
+    normalized
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self::unrestricted()
+    }
+}
+
 pub struct Context {
     programs: Vec<(ProgramBundle, Option<Rc<Path>>)>,
     libs: HashMap<VMString, Value>,
-    file_libs: HashMap<Rc<Path>, Value>
+    // libraries of the VM itself, which files of the same name override
+    builtin_libs: HashMap<VMString, Value>,
+    file_libs: HashMap<Rc<Path>, Value>,
+    // what each program's imports resolved to, by program index and name
+    resolved_libs: HashMap<(usize, VMString), Value>,
+    lib_paths: Vec<PathBuf>,
+    loading: Vec<Rc<Path>>,
+    // parsed `cute.toml` of each directory looked at, `None` if it has none
+    manifests: HashMap<PathBuf, Option<Rc<Manifest>>>,
+    warnings: Vec<VMWarning>,
+    cache: Option<CompileCache>,
+    sandbox: Sandbox
 }
 
 impl Context {
     pub fn new(program: ProgramBundle, path: Option<Rc<Path>>) -> Self {
-        Self {
+        let mut ctx = Self {
+            // the main program counts as being loaded while it runs
+            loading: path.iter().cloned().collect(),
             programs: vec![(program, path)],
             libs: HashMap::new(),
-            file_libs: HashMap::new()
-        }
+            builtin_libs: HashMap::new(),
+            file_libs: HashMap::new(),
+            resolved_libs: HashMap::new(),
+            lib_paths: vec![],
+            manifests: HashMap::new(),
+            warnings: vec![],
+            cache: None,
+            sandbox: Sandbox::default()
+        };
+        builtins::register(&mut ctx);
+        ctx
+    }
+
+    pub fn cache(&self) -> Option<&CompileCache> {
+        self.cache.as_ref()
+    }
+
+    pub fn set_cache(&mut self, cache: Option<CompileCache>) {
+        self.cache = cache;
+    }
+
+    pub fn sandbox(&self) -> &Sandbox {
+        &self.sandbox
+    }
+
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.sandbox = sandbox;
     }
 
     pub fn add_program(&mut self, program: ProgramBundle, path: Option<Rc<Path>>) -> usize {
@@ -424,6 +1217,22 @@ impl Context {
         &self.libs
     }
 
+    pub fn add_builtin_lib(&mut self, name: VMString, lib: Value) {
+        self.builtin_libs.insert(name, lib);
+    }
+
+    pub fn get_builtin_lib(&self, name: &VMString) -> Option<&Value> {
+        self.builtin_libs.get(name)
+    }
+
+    pub fn add_resolved_lib(&mut self, program_idx: usize, name: VMString, lib: Value) {
+        self.resolved_libs.insert((program_idx, name), lib);
+    }
+
+    pub fn get_resolved_lib(&self, program_idx: usize, name: &VMString) -> Option<&Value> {
+        self.resolved_libs.get(&(program_idx, name.clone()))
+    }
+
     pub fn add_file_lib(&mut self, path: Rc<Path>, lib: Value) {
         self.file_libs.insert(path, lib);
     }
@@ -435,11 +1244,57 @@ impl Context {
     pub fn get_file_libs(&self) -> &HashMap<Rc<Path>, Value> {
         &self.file_libs
     }
+
+    pub fn add_manifest(&mut self, dir: PathBuf, manifest: Option<Rc<Manifest>>) {
+        self.manifests.insert(dir, manifest);
+    }
+
+    /// The manifest of `dir` if already looked for, `Some(None)` when it has none.
+    pub fn get_manifest(&self, dir: &Path) -> Option<Option<Rc<Manifest>>> {
+        self.manifests.get(dir).cloned()
+    }
+
+    pub fn warn(&mut self, warning: VMWarning) {
+        self.warnings.push(warning);
+    }
+
+    /// Takes the warnings collected so far.
+    pub fn take_warnings(&mut self) -> Vec<VMWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Marks a file library as in-progress, failing with the import chain
+    /// if it is already being loaded further up.
+    pub fn begin_loading(&mut self, path: Rc<Path>) -> Result<(), VMError> {
+        if let Some(pos) = self.loading.iter().position(|p| *p == path) {
+            let mut chain: Vec<_> = self.loading[pos..].iter().map(|p| p.to_path_buf()).collect();
+            chain.push(path.to_path_buf());
+            return Err(VMError::CircularImport(chain));
+        }
+        self.loading.push(path);
+        Ok(())
+    }
+
+    pub fn end_loading(&mut self) {
+        self.loading.pop();
+    }
+
+    /// Appends a directory to the library search path, which is consulted
+    /// in order after the importing program's own directory.
+    pub fn add_lib_path(&mut self, dir: PathBuf) {
+        self.lib_paths.push(dir);
+    }
+
+    pub fn get_lib_paths(&self) -> &[PathBuf] {
+        &self.lib_paths
+    }
 }
 
 pub struct ProgramState {
     pub program_idx: usize,
     pub func_idx: usize,
     pub variables: Gc<Variables>,
-    pub args: Vec<Value>
+    pub args: Vec<Value>,
+    // the object a method was called on, null for a plain call
+    pub receiver: Value
 }